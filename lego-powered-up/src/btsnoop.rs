@@ -0,0 +1,360 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Reading btsnoop HCI logs, such as Android's `btsnoop_hci.log`, and
+//! picking the LWP3 traffic out of them, to compare what other apps send
+//! to a hub with what we send.
+
+use crate::capture::{Capture, CaptureEntry, Direction};
+use crate::consts::blecharacteristic::LPF2_ALL;
+use crate::error::{Error, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::time::Duration;
+use uuid::Uuid;
+
+const MAGIC: &[u8; 8] = b"btsnoop\0";
+/// HCI packets without a packet type indicator
+const DATALINK_HCI: u32 = 1001;
+/// HCI packets prefixed with their UART (H4) packet type
+const DATALINK_H4: u32 = 1002;
+const H4_ACL: u8 = 0x02;
+const RECORD_HEADER_LEN: usize = 24;
+/// Longest record we accept. HCI packets are far shorter, so anything
+/// longer means the log is corrupt.
+const MAX_RECORD_LEN: usize = 0x10000;
+
+/// Packet flag bits
+const FLAG_RECEIVED: u32 = 0x01;
+const FLAG_COMMAND_OR_EVENT: u32 = 0x02;
+
+/// ACL packet boundary flag of a continuation fragment
+const ACL_CONTINUATION: u16 = 0x01;
+/// L2CAP channel of the attribute protocol
+const ATT_CID: u16 = 0x0004;
+
+const ATT_READ_BY_TYPE_RESPONSE: u8 = 0x09;
+const ATT_WRITE_REQUEST: u8 = 0x12;
+const ATT_HANDLE_VALUE_NOTIFICATION: u8 = 0x1b;
+const ATT_HANDLE_VALUE_INDICATION: u8 = 0x1d;
+const ATT_WRITE_COMMAND: u8 = 0x52;
+/// Size of a characteristic declaration with a 128 bit UUID in a Read By
+/// Type response
+const CHARACTERISTIC_128_LEN: usize = 21;
+
+/// One packet of the log
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// Microseconds since midnight, January 1st 0 AD
+    pub timestamp: i64,
+    pub direction: Direction,
+    /// Whether this is an HCI command or event rather than data
+    pub command_or_event: bool,
+    /// The packet, without any H4 packet type
+    pub data: Vec<u8>,
+}
+
+/// A btsnoop log
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Btsnoop {
+    pub records: Vec<Record>,
+}
+
+impl Btsnoop {
+    pub fn read(mut reader: impl Read) -> Result<Self> {
+        let mut header = [0; 16];
+        reader.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(Error::ParseError("Not a btsnoop log".to_string()));
+        }
+        let datalink = u32::from_be_bytes(header[12..16].try_into().unwrap());
+        if datalink != DATALINK_HCI && datalink != DATALINK_H4 {
+            return Err(Error::ParseError(format!(
+                "Unsupported btsnoop datalink type {}",
+                datalink
+            )));
+        }
+
+        let mut records = Vec::new();
+        loop {
+            let mut header = Vec::with_capacity(RECORD_HEADER_LEN);
+            reader
+                .by_ref()
+                .take(RECORD_HEADER_LEN as u64)
+                .read_to_end(&mut header)?;
+            if header.is_empty() {
+                break;
+            }
+            if header.len() < RECORD_HEADER_LEN {
+                return Err(truncated());
+            }
+            let field = |i: usize| {
+                u32::from_be_bytes(header[i..i + 4].try_into().unwrap())
+            };
+            let included_len = field(4) as usize;
+            let flags = field(8);
+            let timestamp =
+                i64::from_be_bytes(header[16..].try_into().unwrap());
+            if included_len > MAX_RECORD_LEN {
+                return Err(Error::ParseError(format!(
+                    "btsnoop record of {} bytes is too long",
+                    included_len
+                )));
+            }
+
+            let mut data = Vec::with_capacity(included_len);
+            reader
+                .by_ref()
+                .take(included_len as u64)
+                .read_to_end(&mut data)?;
+            if data.len() < included_len {
+                return Err(truncated());
+            }
+            let mut command_or_event = flags & FLAG_COMMAND_OR_EVENT != 0;
+            if datalink == DATALINK_H4 {
+                if data.is_empty() {
+                    continue;
+                }
+                command_or_event = data.remove(0) != H4_ACL;
+            }
+            records.push(Record {
+                timestamp,
+                direction: if flags & FLAG_RECEIVED != 0 {
+                    Direction::Received
+                } else {
+                    Direction::Sent
+                },
+                command_or_event,
+                data,
+            });
+        }
+        Ok(Self { records })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    /// The values written to and notified from the LWP3 characteristic, with
+    /// times relative to the start of the log.
+    ///
+    /// The characteristic is found from the service discovery in the log,
+    /// for each connection, unless its attribute `handle` is given. Logs
+    /// that start after discovery need the handle.
+    pub fn lwp3_frames(&self, handle: Option<u16>) -> Result<Capture> {
+        let start = self.records.first().map_or(0, |r| r.timestamp);
+        let mut handles: HashMap<u16, u16> = HashMap::new();
+        let mut fragments: HashMap<(u16, Direction), Vec<u8>> = HashMap::new();
+        let mut entries = Vec::new();
+
+        for record in self.records.iter().filter(|r| !r.command_or_event) {
+            let Some((connection, att)) = reassemble(&mut fragments, record)
+            else {
+                continue;
+            };
+            let Some((&opcode, params)) = att.split_first() else {
+                continue;
+            };
+            match opcode {
+                ATT_READ_BY_TYPE_RESPONSE => {
+                    if let Some(value_handle) = lpf2_declaration(params) {
+                        handles.insert(connection, value_handle);
+                    }
+                }
+                ATT_WRITE_REQUEST
+                | ATT_WRITE_COMMAND
+                | ATT_HANDLE_VALUE_NOTIFICATION
+                | ATT_HANDLE_VALUE_INDICATION
+                    if params.len() >= 2 =>
+                {
+                    let attribute = u16::from_le_bytes([params[0], params[1]]);
+                    if handle.or_else(|| handles.get(&connection).copied())
+                        == Some(attribute)
+                    {
+                        entries.push(CaptureEntry {
+                            time: Duration::from_micros(
+                                record.timestamp.saturating_sub(start).max(0)
+                                    as u64,
+                            ),
+                            direction: record.direction,
+                            frame: params[2..].to_vec(),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if handle.is_none() && handles.is_empty() {
+            return Err(Error::NoneError(
+                "No LPF2_ALL characteristic discovered in log".to_string(),
+            ));
+        }
+        Ok(Capture { entries })
+    }
+}
+
+fn truncated() -> Error {
+    Error::ParseError("Truncated btsnoop record".to_string())
+}
+
+/// Collect ACL fragments until they make up a whole L2CAP PDU, returning
+/// the connection handle and the PDU's payload if it is on the ATT channel
+fn reassemble(
+    fragments: &mut HashMap<(u16, Direction), Vec<u8>>,
+    record: &Record,
+) -> Option<(u16, Vec<u8>)> {
+    let data = &record.data;
+    if data.len() < 4 {
+        return None;
+    }
+    let header = u16::from_le_bytes([data[0], data[1]]);
+    let connection = header & 0x0fff;
+    let key = (connection, record.direction);
+    let payload = &data[4..];
+
+    let buffer = if (header >> 12) & 0x03 == ACL_CONTINUATION {
+        let buffer = fragments.get_mut(&key)?;
+        buffer.extend_from_slice(payload);
+        buffer
+    } else {
+        fragments.insert(key, payload.to_vec());
+        fragments.get_mut(&key).unwrap()
+    };
+    if buffer.len() < 4 {
+        return None;
+    }
+    let length = u16::from_le_bytes([buffer[0], buffer[1]]) as usize;
+    if buffer.len() < length + 4 {
+        return None;
+    }
+    let pdu = fragments.remove(&key).unwrap();
+    let cid = u16::from_le_bytes([pdu[2], pdu[3]]);
+    (cid == ATT_CID).then(|| (connection, pdu[4..length + 4].to_vec()))
+}
+
+/// Value handle of the LPF2_ALL characteristic, if it is among the
+/// characteristic declarations in a Read By Type response
+fn lpf2_declaration(params: &[u8]) -> Option<u16> {
+    let (&len, declarations) = params.split_first()?;
+    if len as usize != CHARACTERISTIC_128_LEN {
+        return None;
+    }
+    declarations
+        .chunks_exact(len as usize)
+        .find_map(|declaration| {
+            // Handle, properties, value handle, then the UUID little endian
+            let mut uuid: [u8; 16] = declaration[5..].try_into().unwrap();
+            uuid.reverse();
+            (Uuid::from_bytes(uuid) == *LPF2_ALL)
+                .then(|| u16::from_le_bytes([declaration[3], declaration[4]]))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CONNECTION: u16 = 0x0040;
+
+    fn log(records: &[(u32, u16, Vec<u8>)]) -> Vec<u8> {
+        let mut log = MAGIC.to_vec();
+        log.extend(1u32.to_be_bytes());
+        log.extend(DATALINK_H4.to_be_bytes());
+        for (i, (flags, pb, l2cap)) in records.iter().enumerate() {
+            let mut acl = vec![H4_ACL];
+            acl.extend((CONNECTION | pb << 12).to_le_bytes());
+            acl.extend((l2cap.len() as u16).to_le_bytes());
+            acl.extend(l2cap);
+            log.extend((acl.len() as u32).to_be_bytes());
+            log.extend((acl.len() as u32).to_be_bytes());
+            log.extend(flags.to_be_bytes());
+            log.extend(0u32.to_be_bytes());
+            log.extend((0x00e0_0000_0000_0000 + i as i64 * 1000).to_be_bytes());
+            log.extend(acl);
+        }
+        log
+    }
+
+    fn att(pdu: &[u8]) -> Vec<u8> {
+        let mut l2cap = (pdu.len() as u16).to_le_bytes().to_vec();
+        l2cap.extend(ATT_CID.to_le_bytes());
+        l2cap.extend(pdu);
+        l2cap
+    }
+
+    #[test]
+    fn lwp3_frames() {
+        let mut discovery =
+            vec![ATT_READ_BY_TYPE_RESPONSE, 21, 0x0d, 0x00, 0x1e];
+        discovery.extend(0x000eu16.to_le_bytes());
+        discovery.extend(LPF2_ALL.as_bytes().iter().rev());
+
+        let write = att(&[ATT_WRITE_COMMAND, 0x0e, 0x00, 5, 0, 1, 6, 5]);
+        let notification = att(&[
+            ATT_HANDLE_VALUE_NOTIFICATION,
+            0x0e,
+            0x00,
+            6,
+            0,
+            1,
+            6,
+            6,
+            100,
+        ]);
+        let other = att(&[ATT_HANDLE_VALUE_NOTIFICATION, 0x10, 0x00, 1]);
+
+        let records = [
+            (FLAG_RECEIVED, 0x2, att(&discovery)),
+            (0, 0x0, write),
+            (FLAG_RECEIVED, 0x2, notification[..6].to_vec()),
+            (FLAG_RECEIVED, 0x1, notification[6..].to_vec()),
+            (FLAG_RECEIVED, 0x2, other),
+        ];
+        let btsnoop = Btsnoop::read(log(&records).as_slice()).unwrap();
+        assert_eq!(btsnoop.records.len(), 5);
+
+        let capture = btsnoop.lwp3_frames(None).unwrap();
+        assert_eq!(
+            capture.entries,
+            [
+                CaptureEntry {
+                    time: Duration::from_millis(1),
+                    direction: Direction::Sent,
+                    frame: vec![5, 0, 1, 6, 5],
+                },
+                CaptureEntry {
+                    time: Duration::from_millis(3),
+                    direction: Direction::Received,
+                    frame: vec![6, 0, 1, 6, 6, 100],
+                },
+            ]
+        );
+
+        let capture = btsnoop.lwp3_frames(Some(0x0010)).unwrap();
+        assert_eq!(capture.entries.len(), 1);
+
+        let undiscovered =
+            Btsnoop::read(log(&records[1..]).as_slice()).unwrap();
+        assert!(undiscovered.lwp3_frames(None).is_err());
+        assert!(Btsnoop::read(&b"btsnoop"[..]).is_err());
+
+        // Partial record header, partial record and an oversized record
+        let whole = log(&records[..1]);
+        for len in [whole.len() - 1, 16 + 10] {
+            assert!(matches!(
+                Btsnoop::read(&whole[..len]),
+                Err(Error::ParseError(_))
+            ));
+        }
+        let mut oversized = whole;
+        oversized[16 + 4..16 + 8].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(
+            Btsnoop::read(oversized.as_slice()),
+            Err(Error::ParseError(_))
+        ));
+    }
+}
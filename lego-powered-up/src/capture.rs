@@ -0,0 +1,453 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Recording hub sessions to a file and replaying them, so that something
+//! seen on a real hub can be reproduced without it.
+//!
+//! A capture is a text file with one frame per line: the time since the
+//! recording started in seconds, `>` for a frame sent to the hub or `<`
+//! for one received from it, and the frame's bytes in hex.
+//!
+//! ```text
+//! # lego-powered-up capture v1
+//! 0.000000 > 05 00 01 06 05
+//! 0.031250 < 06 00 01 06 06 64
+//! ```
+
+use crate::error::{Error, Result};
+use crate::hubs::NotificationStream;
+use crate::transport::{Link, Transport};
+use async_trait::async_trait;
+use futures::stream::StreamExt;
+use log::{debug, warn};
+use std::fmt::{self, Debug, Display};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+const HEADER: &str = "# lego-powered-up capture v1";
+
+/// Which way a frame went
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// From us to the hub
+    Sent,
+    /// From the hub to us
+    Received,
+}
+
+/// One frame of a capture
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CaptureEntry {
+    /// Time since the recording started
+    pub time: Duration,
+    pub direction: Direction,
+    pub frame: Vec<u8>,
+}
+
+impl Display for CaptureEntry {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let direction = match self.direction {
+            Direction::Sent => '>',
+            Direction::Received => '<',
+        };
+        write!(fmt, "{:.6} {}", self.time.as_secs_f64(), direction)?;
+        for byte in &self.frame {
+            write!(fmt, " {:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for CaptureEntry {
+    type Err = Error;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let bad_line =
+            || Error::ParseError(format!("Bad capture line: {line}"));
+        let mut fields = line.split_whitespace();
+        let time = fields
+            .next()
+            .and_then(|t| t.parse::<f64>().ok())
+            .and_then(|t| Duration::try_from_secs_f64(t).ok())
+            .ok_or_else(bad_line)?;
+        let direction = match fields.next() {
+            Some(">") => Direction::Sent,
+            Some("<") => Direction::Received,
+            _ => return Err(bad_line()),
+        };
+        let frame = fields
+            .map(|byte| u8::from_str_radix(byte, 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .map_err(|_| bad_line())?;
+        Ok(Self {
+            time,
+            direction,
+            frame,
+        })
+    }
+}
+
+/// A recorded session
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Capture {
+    pub entries: Vec<CaptureEntry>,
+}
+
+impl Capture {
+    /// Read a capture, skipping blank lines and `#` comments
+    pub fn read(reader: impl BufRead) -> Result<Self> {
+        let mut entries = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            entries.push(line.parse()?);
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    pub fn write(&self, mut writer: impl Write) -> Result<()> {
+        writeln!(writer, "{}", HEADER)?;
+        for entry in &self.entries {
+            writeln!(writer, "{}", entry)?;
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        Ok(writer.flush()?)
+    }
+}
+
+/// Where a recording goes, shared between the transport and its streams
+#[derive(Debug)]
+struct Recorder<W> {
+    start: Instant,
+    writer: Mutex<W>,
+}
+
+impl<W: Write> Recorder<W> {
+    /// Write straight through, so the capture survives a crash
+    fn record(&self, direction: Direction, frame: &[u8]) {
+        let entry = CaptureEntry {
+            time: self.start.elapsed(),
+            direction,
+            frame: frame.to_vec(),
+        };
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(e) =
+            writeln!(writer, "{}", entry).and_then(|_| writer.flush())
+        {
+            warn!("Failed to record frame: {}", e);
+        }
+    }
+}
+
+/// Transport that records everything passing through another one. Frames
+/// are recorded as they pass through the notification streams, and hubs
+/// open a single stream, so each is recorded once.
+#[derive(Debug)]
+pub struct RecordingTransport<W> {
+    inner: Arc<dyn Transport>,
+    recorder: Arc<Recorder<W>>,
+}
+
+impl RecordingTransport<BufWriter<File>> {
+    /// Record to a new capture file
+    pub fn create(
+        inner: Arc<dyn Transport>,
+        path: impl AsRef<Path>,
+    ) -> Result<Self> {
+        Self::new(inner, BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<W: Write + Debug + Send + 'static> RecordingTransport<W> {
+    pub fn new(inner: Arc<dyn Transport>, mut writer: W) -> Result<Self> {
+        writeln!(writer, "{}", HEADER)?;
+        writer.flush()?;
+        Ok(Self {
+            inner,
+            recorder: Arc::new(Recorder {
+                start: Instant::now(),
+                writer: Mutex::new(writer),
+            }),
+        })
+    }
+}
+
+#[async_trait]
+impl<W: Write + Debug + Send + 'static> Transport for RecordingTransport<W> {
+    async fn send(&self, frame: &[u8]) -> Result<()> {
+        self.recorder.record(Direction::Sent, frame);
+        self.inner.send(frame).await
+    }
+
+    async fn notifications(&self) -> Result<NotificationStream<Vec<u8>>> {
+        let recorder = self.recorder.clone();
+        let notifications = self.inner.notifications().await?;
+        Ok(Box::pin(notifications.inspect(move |frame| {
+            recorder.record(Direction::Received, frame)
+        })))
+    }
+
+    async fn subscribe(&self) -> Result<()> {
+        self.inner.subscribe().await
+    }
+
+    async fn is_connected(&self) -> Result<bool> {
+        self.inner.is_connected().await
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        self.inner.disconnect().await
+    }
+}
+
+/// Transport that plays a capture back in place of a hub.
+///
+/// Playback starts when notifications are subscribed to, and runs in step
+/// with the library: received frames are played with their recorded
+/// spacing, and at each sent frame playback waits until the library sends
+/// one. Frames that don't match the recording are counted as mismatches
+/// and logged, and playback carries on.
+#[derive(Debug)]
+pub struct ReplayTransport {
+    link: Arc<Link>,
+    playback: Mutex<Option<Playback>>,
+    sent: mpsc::UnboundedSender<Vec<u8>>,
+    mismatches: Arc<AtomicUsize>,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[derive(Debug)]
+struct Playback {
+    entries: Vec<CaptureEntry>,
+    sent: mpsc::UnboundedReceiver<Vec<u8>>,
+    /// Whether to keep the recorded spacing between frames
+    realtime: bool,
+}
+
+impl ReplayTransport {
+    pub fn new(capture: Capture) -> Self {
+        let (sent, receiver) = mpsc::unbounded_channel();
+        Self {
+            link: Arc::new(Link::new()),
+            playback: Mutex::new(Some(Playback {
+                entries: capture.entries,
+                sent: receiver,
+                realtime: true,
+            })),
+            sent,
+            mismatches: Default::default(),
+            task: Mutex::new(None),
+        }
+    }
+
+    /// Play received frames as fast as possible instead of with their
+    /// recorded spacing
+    pub fn without_delays(self) -> Self {
+        if let Some(playback) = self.playback.lock().unwrap().as_mut() {
+            playback.realtime = false;
+        }
+        self
+    }
+
+    /// Number of sent frames so far that didn't match the recording
+    pub fn mismatches(&self) -> usize {
+        self.mismatches.load(Ordering::SeqCst)
+    }
+}
+
+impl Playback {
+    async fn run(mut self, link: Arc<Link>, mismatches: Arc<AtomicUsize>) {
+        let mut previous = Duration::ZERO;
+        for entry in self.entries {
+            let gap = entry.time.saturating_sub(previous);
+            previous = entry.time;
+            match entry.direction {
+                Direction::Received => {
+                    if self.realtime && !gap.is_zero() {
+                        tokio::time::sleep(gap).await;
+                    }
+                    link.send(entry.frame);
+                }
+                Direction::Sent => {
+                    let Some(frame) = self.sent.recv().await else {
+                        return;
+                    };
+                    if frame != entry.frame {
+                        warn!(
+                            "Replay expected {:02x?} to be sent but got {:02x?}",
+                            entry.frame, frame
+                        );
+                        mismatches.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            }
+        }
+        debug!("Replay finished");
+    }
+}
+
+#[async_trait]
+impl Transport for ReplayTransport {
+    async fn send(&self, frame: &[u8]) -> Result<()> {
+        if !self.link.is_connected() {
+            return Err(Error::HubError("Replay is disconnected".to_string()));
+        }
+        // Frames sent after the end of the recording go nowhere
+        let _ = self.sent.send(frame.to_vec());
+        Ok(())
+    }
+
+    async fn notifications(&self) -> Result<NotificationStream<Vec<u8>>> {
+        self.link.notifications()
+    }
+
+    async fn subscribe(&self) -> Result<()> {
+        if let Some(playback) = self.playback.lock().unwrap().take() {
+            let task = tokio::spawn(
+                playback.run(self.link.clone(), self.mismatches.clone()),
+            );
+            *self.task.lock().unwrap() = Some(task);
+        }
+        Ok(())
+    }
+
+    async fn is_connected(&self) -> Result<bool> {
+        Ok(self.link.is_connected())
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        if let Some(task) = self.task.lock().unwrap().take() {
+            task.abort();
+        }
+        self.link.close();
+        Ok(())
+    }
+}
+
+impl Drop for ReplayTransport {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.lock().unwrap().take() {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::consts::HubType;
+    use crate::hubs::{Hub, HubProperties, Port, TechnicHub};
+    use crate::notifications::IoTypeId;
+    use crate::sim::SimHub;
+
+    /// Capture file contents that the test can still read once the
+    /// transport has them
+    #[derive(Clone, Debug, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn capture_lines() {
+        let entry = CaptureEntry {
+            time: Duration::from_micros(1_500_250),
+            direction: Direction::Received,
+            frame: vec![0x05, 0x00, 0x45, 0x00, 0xff],
+        };
+        assert_eq!(entry.to_string(), "1.500250 < 05 00 45 00 ff");
+        assert_eq!(entry.to_string().parse::<CaptureEntry>().unwrap(), entry);
+
+        let capture = Capture::read(
+            "# comment\n\n0 > 05 00 01 06 05\n0.1 < 06 00 01 06 06 64\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(capture.entries.len(), 2);
+        assert_eq!(capture.entries[0].direction, Direction::Sent);
+        assert_eq!(capture.entries[1].frame[5], 0x64);
+
+        for bad in [
+            "",
+            "x > 05",
+            "0 = 05",
+            "0 > 5g",
+            "-1 > 05",
+            "1e30 > 05",
+            "0 > 100",
+        ] {
+            assert!(bad.parse::<CaptureEntry>().is_err(), "{}", bad);
+        }
+    }
+
+    async fn battery_and_devices(transport: Arc<dyn Transport>) -> u8 {
+        let hub = TechnicHub::with_transport(
+            transport,
+            HubType::TechnicMediumHub,
+            HubProperties::default(),
+        )
+        .await
+        .unwrap();
+        hub.wait_for_device(
+            Port::A,
+            crate::consts::DeviceType::MediumLinearMotor,
+        )
+        .await
+        .unwrap();
+        let voltage = hub.battery_voltage().await.unwrap();
+        hub.disconnect().await.unwrap();
+        voltage
+    }
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        let sim = SimHub::technic_medium_hub("Sim")
+            .with_device(Port::A, IoTypeId::ExternalMotor);
+        let file = Shared::default();
+        let recording =
+            RecordingTransport::new(Arc::new(sim.connect()), file.clone())
+                .unwrap();
+        assert_eq!(battery_and_devices(Arc::new(recording)).await, 100);
+
+        let capture = Capture::read(file.0.lock().unwrap().as_slice()).unwrap();
+        let sent: Vec<_> = capture
+            .entries
+            .iter()
+            .filter(|e| e.direction == Direction::Sent)
+            .collect();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].frame, [0x05, 0x00, 0x01, 0x06, 0x05]);
+        assert!(capture.entries.len() > 10);
+
+        let replay = Arc::new(ReplayTransport::new(capture).without_delays());
+        assert_eq!(battery_and_devices(replay.clone()).await, 100);
+        assert_eq!(replay.mismatches(), 0);
+    }
+}
@@ -5,7 +5,10 @@
 //! Various constants defined by the specification, but translated into Rust
 //! types
 
+use crate::error::{OptionContext, Result};
+use lpu_macros::{Parse, Serialise};
 use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
 use std::fmt::{self, Display};
 
 /// ```ignore
@@ -33,7 +36,7 @@ pub enum HubType {
 }
 
 impl Display for HubType {
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         use HubType::*;
         match self {
             Unknown | MoveHub | Hub | Mario => write!(fmt, "{:?}", self),
@@ -73,7 +76,7 @@ impl Display for HubType {
 /// @property {number} CONTROL_PLUS_TILT 59
 /// ```
 #[repr(u8)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive)]
 pub enum DeviceType {
     Unknown = 0,
     SimpleMediumLinearMotor = 1,
@@ -267,7 +270,9 @@ pub mod blecharacteristic {
 /// @description <https://lego.github.io/lego-ble-wireless-protocol-docs/index.html#message-types>
 /// ```
 #[repr(u8)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse, Serialise,
+)]
 pub enum MessageType {
     HubProperties = 0x01,
     HubActions = 0x02,
@@ -429,7 +434,7 @@ pub enum ActionType {
 /// @description <https://lego.github.io/lego-ble-wireless-protocol-docs/index.html#alert-payload>
 /// ```
 #[repr(u8)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive)]
 pub enum AlertPayload {
     StatusOk = 0x00,
     Alert = 0xFF,
@@ -6,11 +6,15 @@
 
 use crate::error::{Error, Result};
 use crate::hubs::Port;
-use crate::notifications::{HubLedMode, NotificationMessage, Power};
+use crate::notifications::{
+    CalibrationOrientation, CompletionInfo, HubLedMode, IoTypeId,
+    NotificationMessage, PortOutputCommandFormat, PortOutputSubcommand, Power,
+    StartupInfo, WriteDirectModeDataPayload, WriteDirectPayload,
+};
+use crate::transport::Transport;
 use async_trait::async_trait;
-use btleplug::api::{Characteristic, Peripheral as _, WriteType};
-use btleplug::platform::Peripheral;
 use std::fmt::Debug;
+use std::sync::Arc;
 
 /// Trait that any device may implement. Having a single trait covering
 /// every device is probably the wrong design, and we should have better
@@ -18,14 +22,34 @@ use std::fmt::Debug;
 #[async_trait]
 pub trait Device: Debug + Send + Sync {
     fn port(&self) -> Port;
-    fn peripheral(&self) -> &Peripheral;
-    fn characteristic(&self) -> &Characteristic;
+    fn port_id(&self) -> u8;
+    /// Type of the attached device, as reported by the hub
+    fn io_type_id(&self) -> IoTypeId;
+    fn transport(&self) -> &dyn Transport;
     async fn send(&mut self, msg: NotificationMessage) -> Result<()> {
-        let buf = msg.serialise();
-        self.peripheral()
-            .write(self.characteristic(), &buf, WriteType::WithoutResponse)
-            .await?;
-        Ok(())
+        let buf = msg.try_serialise()?;
+        self.transport().send(&buf).await
+    }
+    /// Send an output command to the device's port. The startup info
+    /// selects whether the hub may buffer the command behind one that is
+    /// already running, and the completion info whether the hub reports
+    /// back with a PortOutputCommandFeedback message. Returns once the
+    /// command is sent, so feedback and any error the hub replies with
+    /// arrive as notifications.
+    async fn output_command(
+        &mut self,
+        subcommand: PortOutputSubcommand,
+        startup_info: StartupInfo,
+        completion_info: CompletionInfo,
+    ) -> Result<()> {
+        let msg =
+            NotificationMessage::PortOutputCommand(PortOutputCommandFormat {
+                port_id: self.port_id(),
+                startup_info,
+                completion_info,
+                subcommand,
+            });
+        self.send(msg).await
     }
     async fn set_rgb(&mut self, _rgb: &[u8; 3]) -> Result<()> {
         Err(Error::NotImplementedError(
@@ -41,6 +65,86 @@ pub trait Device: Debug + Send + Sync {
             "Not implemented for type".to_string(),
         ))
     }
+    /// Drive a motor or light at the given power, without speed control
+    async fn start_power(&mut self, _power: Power) -> Result<()> {
+        Err(Error::NotImplementedError(
+            "Not implemented for type".to_string(),
+        ))
+    }
+    /// Recalibrate a tilt sensor to its factory settings, with the hub
+    /// in the given orientation
+    async fn tilt_factory_calibration(
+        &mut self,
+        _orientation: CalibrationOrientation,
+    ) -> Result<()> {
+        Err(Error::NotImplementedError(
+            "Not implemented for type".to_string(),
+        ))
+    }
+    async fn tilt_hardware_reset(&mut self) -> Result<()> {
+        Err(Error::NotImplementedError(
+            "Not implemented for type".to_string(),
+        ))
+    }
+}
+
+/// Create a handle for the device attached to a port, of the concrete type
+/// matching the attached device
+pub(crate) fn create_device(
+    io_type_id: IoTypeId,
+    transport: Arc<dyn Transport>,
+    port: Port,
+    port_id: u8,
+) -> Result<Box<dyn Device>> {
+    Ok(match io_type_id {
+        IoTypeId::Motor
+        | IoTypeId::SystemTrainMotor
+        | IoTypeId::ExternalMotor
+        | IoTypeId::InternalMotor
+        | IoTypeId::DuploTrainBaseMotor
+        | IoTypeId::TechnicLargeLinearMotor
+        | IoTypeId::TechnicXlargeLinearMotor
+        | IoTypeId::TechnicMediumAngularMotor
+        | IoTypeId::TechnicLargeAngularMotor
+        | IoTypeId::TechnicMediumAngularMotorGrey
+        | IoTypeId::TechnicLargeAngularMotorGrey => {
+            Box::new(Motor::new(transport, port, port_id, io_type_id))
+        }
+        IoTypeId::RgbLight => Box::new(HubLED::new(transport, port_id)),
+        IoTypeId::LedLight => Box::new(Light::new(transport, port, port_id)),
+        IoTypeId::TechnicMediumHubTiltSensor => {
+            Box::new(TiltSensor::new(transport, port, port_id))
+        }
+        IoTypeId::Button
+        | IoTypeId::Voltage
+        | IoTypeId::Current
+        | IoTypeId::ExternalTiltSensor
+        | IoTypeId::MotionSensor
+        | IoTypeId::VisionSensor
+        | IoTypeId::InternalTilt
+        | IoTypeId::DuploTrainBaseColorSensor
+        | IoTypeId::DuploTrainBaseSpeedometer
+        | IoTypeId::TechnicMediumHubGestSensor
+        | IoTypeId::RemoteControlButton
+        | IoTypeId::RemoteControlRssi
+        | IoTypeId::TechnicMediumHubAccelerometer
+        | IoTypeId::TechnicMediumHubGyroSensor
+        | IoTypeId::TechnicMediumHubTemperatureSensor
+        | IoTypeId::TechnicColorSensor
+        | IoTypeId::TechnicDistanceSensor
+        | IoTypeId::TechnicForceSensor
+        | IoTypeId::MarioAccelerometer
+        | IoTypeId::MarioBarcodeSensor
+        | IoTypeId::MarioPantsSensor => {
+            Box::new(Sensor::new(transport, port, port_id, io_type_id))
+        }
+        IoTypeId::PiezoToneSound | IoTypeId::DuploTrainBaseSpeaker => {
+            return Err(Error::NotImplementedError(format!(
+                "No device handle for {:?} on port {:?}",
+                io_type_id, port
+            )))
+        }
+    })
 }
 
 /// Struct representing a Hub LED
@@ -49,8 +153,7 @@ pub struct HubLED {
     /// RGB colour value
     rgb: [u8; 3],
     _mode: HubLedMode,
-    peripheral: Peripheral,
-    characteristic: Characteristic,
+    transport: Arc<dyn Transport>,
     port_id: u8,
 }
 
@@ -60,12 +163,16 @@ impl Device for HubLED {
         Port::HubLed
     }
 
-    fn peripheral(&self) -> &Peripheral {
-        &self.peripheral
+    fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    fn io_type_id(&self) -> IoTypeId {
+        IoTypeId::RgbLight
     }
 
-    fn characteristic(&self) -> &Characteristic {
-        &self.characteristic
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
     }
 
     async fn set_rgb(&mut self, rgb: &[u8; 3]) -> Result<()> {
@@ -75,7 +182,7 @@ impl Device for HubLED {
 
         let mode_set_msg =
             NotificationMessage::PortInputFormatSetupSingle(InputSetupSingle {
-                port_id: 50,
+                port_id: self.port_id,
                 mode: 0x01,
                 delta: 0x00000001,
                 notification_enabled: false,
@@ -89,30 +196,22 @@ impl Device for HubLED {
                 blue: rgb[2],
             },
         );
-
-        let msg =
-            NotificationMessage::PortOutputCommand(PortOutputCommandFormat {
-                port_id: self.port_id,
-                startup_info: StartupInfo::ExecuteImmediately,
-                completion_info: CompletionInfo::NoAction,
-                subcommand,
-            });
-        self.send(msg).await
+        self.output_command(
+            subcommand,
+            StartupInfo::ExecuteImmediately,
+            CompletionInfo::NoAction,
+        )
+        .await
     }
 }
 
 impl HubLED {
-    pub(crate) fn new(
-        peripheral: Peripheral,
-        characteristic: Characteristic,
-        port_id: u8,
-    ) -> Self {
+    pub(crate) fn new(transport: Arc<dyn Transport>, port_id: u8) -> Self {
         let mode = HubLedMode::Rgb;
         Self {
             rgb: [0; 3],
             _mode: mode,
-            characteristic,
-            peripheral,
+            transport,
             port_id,
         }
     }
@@ -121,10 +220,10 @@ impl HubLED {
 /// Struct representing a motor
 #[derive(Debug, Clone)]
 pub struct Motor {
-    peripheral: Peripheral,
-    characteristic: Characteristic,
+    transport: Arc<dyn Transport>,
     port: Port,
     port_id: u8,
+    io_type_id: IoTypeId,
 }
 
 #[async_trait]
@@ -133,46 +232,294 @@ impl Device for Motor {
         self.port
     }
 
-    fn peripheral(&self) -> &Peripheral {
-        &self.peripheral
+    fn port_id(&self) -> u8 {
+        self.port_id
     }
 
-    fn characteristic(&self) -> &Characteristic {
-        &self.characteristic
+    fn io_type_id(&self) -> IoTypeId {
+        self.io_type_id
     }
 
-    async fn start_speed(&mut self, speed: i8, max_power: Power) -> Result<()> {
-        use crate::notifications::*;
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
 
+    async fn start_speed(&mut self, speed: i8, max_power: Power) -> Result<()> {
         let subcommand = PortOutputSubcommand::StartSpeed {
             speed,
             max_power,
             use_acc_profile: true,
             use_dec_profile: true,
         };
-        let msg =
-            NotificationMessage::PortOutputCommand(PortOutputCommandFormat {
-                port_id: self.port_id,
-                startup_info: StartupInfo::ExecuteImmediately,
-                completion_info: CompletionInfo::NoAction,
-                subcommand,
-            });
-        self.send(msg).await
+        self.output_command(
+            subcommand,
+            StartupInfo::ExecuteImmediately,
+            CompletionInfo::NoAction,
+        )
+        .await
+    }
+
+    async fn start_power(&mut self, power: Power) -> Result<()> {
+        let subcommand = PortOutputSubcommand::WriteDirectModeData(
+            WriteDirectModeDataPayload::StartPower(power),
+        );
+        self.output_command(
+            subcommand,
+            StartupInfo::ExecuteImmediately,
+            CompletionInfo::NoAction,
+        )
+        .await
     }
 }
 
 impl Motor {
     pub(crate) fn new(
-        peripheral: Peripheral,
-        characteristic: Characteristic,
+        transport: Arc<dyn Transport>,
+        port: Port,
+        port_id: u8,
+        io_type_id: IoTypeId,
+    ) -> Self {
+        Self {
+            transport,
+            port,
+            port_id,
+            io_type_id,
+        }
+    }
+}
+
+/// Struct representing a tilt sensor
+#[derive(Debug, Clone)]
+pub struct TiltSensor {
+    transport: Arc<dyn Transport>,
+    port: Port,
+    port_id: u8,
+}
+
+#[async_trait]
+impl Device for TiltSensor {
+    fn port(&self) -> Port {
+        self.port
+    }
+
+    fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    fn io_type_id(&self) -> IoTypeId {
+        IoTypeId::TechnicMediumHubTiltSensor
+    }
+
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+
+    async fn tilt_factory_calibration(
+        &mut self,
+        orientation: CalibrationOrientation,
+    ) -> Result<()> {
+        let subcommand = PortOutputSubcommand::WriteDirect(
+            WriteDirectPayload::TiltFactoryCalibration {
+                orientation,
+                pass_code: Self::CALIBRATION_PASS_CODE.to_string(),
+            },
+        );
+        self.output_command(
+            subcommand,
+            StartupInfo::ExecuteImmediately,
+            CompletionInfo::CommandFeedback,
+        )
+        .await
+    }
+
+    async fn tilt_hardware_reset(&mut self) -> Result<()> {
+        let subcommand = PortOutputSubcommand::WriteDirect(
+            WriteDirectPayload::HardwareReset,
+        );
+        self.output_command(
+            subcommand,
+            StartupInfo::ExecuteImmediately,
+            CompletionInfo::CommandFeedback,
+        )
+        .await
+    }
+}
+
+impl TiltSensor {
+    /// The sensor ignores a factory calibration without this pass code
+    const CALIBRATION_PASS_CODE: &'static str = "Calib-Sensor";
+
+    pub(crate) fn new(
+        transport: Arc<dyn Transport>,
+        port: Port,
+        port_id: u8,
+    ) -> Self {
+        Self {
+            transport,
+            port,
+            port_id,
+        }
+    }
+}
+
+/// Struct representing a light, such as the Powered Up LED lights
+#[derive(Debug, Clone)]
+pub struct Light {
+    transport: Arc<dyn Transport>,
+    port: Port,
+    port_id: u8,
+}
+
+#[async_trait]
+impl Device for Light {
+    fn port(&self) -> Port {
+        self.port
+    }
+
+    fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    fn io_type_id(&self) -> IoTypeId {
+        IoTypeId::LedLight
+    }
+
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+
+    /// The brightness is the power in percent; direction doesn't matter
+    async fn start_power(&mut self, power: Power) -> Result<()> {
+        let subcommand = PortOutputSubcommand::WriteDirectModeData(
+            WriteDirectModeDataPayload::StartPower(power),
+        );
+        self.output_command(
+            subcommand,
+            StartupInfo::ExecuteImmediately,
+            CompletionInfo::NoAction,
+        )
+        .await
+    }
+}
+
+impl Light {
+    pub(crate) fn new(
+        transport: Arc<dyn Transport>,
         port: Port,
         port_id: u8,
     ) -> Self {
         Self {
-            peripheral,
-            characteristic,
+            transport,
             port,
             port_id,
         }
     }
 }
+
+/// Struct representing a sensor or other input-only device, such as a
+/// colour sensor or the hub's internal sensors
+#[derive(Debug, Clone)]
+pub struct Sensor {
+    transport: Arc<dyn Transport>,
+    port: Port,
+    port_id: u8,
+    io_type_id: IoTypeId,
+}
+
+#[async_trait]
+impl Device for Sensor {
+    fn port(&self) -> Port {
+        self.port
+    }
+
+    fn port_id(&self) -> u8 {
+        self.port_id
+    }
+
+    fn io_type_id(&self) -> IoTypeId {
+        self.io_type_id
+    }
+
+    fn transport(&self) -> &dyn Transport {
+        self.transport.as_ref()
+    }
+}
+
+impl Sensor {
+    pub(crate) fn new(
+        transport: Arc<dyn Transport>,
+        port: Port,
+        port_id: u8,
+        io_type_id: IoTypeId,
+    ) -> Self {
+        Self {
+            transport,
+            port,
+            port_id,
+            io_type_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sim::SimHub;
+    use num_traits::FromPrimitive;
+
+    /// Name of the concrete device type behind a handle
+    fn kind(device: &dyn Device) -> String {
+        let debug = format!("{:?}", device);
+        debug.split(' ').next().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn device_for_io_type() {
+        let transport: Arc<dyn Transport> =
+            Arc::new(SimHub::technic_medium_hub("Sim").connect());
+        let table = [
+            (IoTypeId::Motor, "Motor"),
+            (IoTypeId::SystemTrainMotor, "Motor"),
+            (IoTypeId::DuploTrainBaseMotor, "Motor"),
+            (IoTypeId::TechnicLargeLinearMotor, "Motor"),
+            (IoTypeId::TechnicLargeAngularMotorGrey, "Motor"),
+            (IoTypeId::RgbLight, "HubLED"),
+            (IoTypeId::LedLight, "Light"),
+            (IoTypeId::TechnicMediumHubTiltSensor, "TiltSensor"),
+            (IoTypeId::Voltage, "Sensor"),
+            (IoTypeId::InternalTilt, "Sensor"),
+            (IoTypeId::TechnicMediumHubGyroSensor, "Sensor"),
+            (IoTypeId::TechnicColorSensor, "Sensor"),
+            (IoTypeId::MarioPantsSensor, "Sensor"),
+        ];
+        for (io_type_id, expected) in table {
+            let device =
+                create_device(io_type_id, transport.clone(), Port::B, 1)
+                    .unwrap();
+            assert_eq!(kind(device.as_ref()), expected, "{:?}", io_type_id);
+            assert_eq!(device.io_type_id(), io_type_id);
+            assert_eq!(device.port_id(), 1);
+        }
+
+        // Every known IO type gets a handle of its own type, except the
+        // speakers, which have none yet
+        for id in 0..=u16::MAX {
+            let Some(io_type_id) = IoTypeId::from_u16(id) else {
+                continue;
+            };
+            let device =
+                create_device(io_type_id, transport.clone(), Port::A, 0);
+            match io_type_id {
+                IoTypeId::PiezoToneSound | IoTypeId::DuploTrainBaseSpeaker => {
+                    let err = device.unwrap_err();
+                    assert!(
+                        matches!(err, Error::NotImplementedError(_)),
+                        "{:?}",
+                        err
+                    );
+                }
+                _ => assert_eq!(device.unwrap().io_type_id(), io_type_id),
+            }
+        }
+    }
+}
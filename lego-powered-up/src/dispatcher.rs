@@ -0,0 +1,428 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Background task that parses a hub's notifications once and fans them
+//! out to any number of subscribers.
+
+use crate::consts::MessageType;
+use crate::error::Error;
+use crate::hubs::NotificationStream;
+use crate::notifications::{
+    CombinedValue, NotificationMessage, TypedValue, ValueFormatRegistry,
+};
+use futures::stream::{Stream, StreamExt};
+use log::{debug, warn};
+use num_traits::FromPrimitive;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinHandle;
+
+/// Number of notifications a subscriber may fall behind before it starts
+/// missing them
+const CHANNEL_CAPACITY: usize = 256;
+
+/// The values in a port value notification, split up using the value
+/// formats the hub has reported
+#[derive(Clone, Debug, PartialEq)]
+pub enum PortValues {
+    /// Datasets of each port in a PortValueSingle message
+    Single(HashMap<u8, Vec<TypedValue>>),
+    /// Datasets present in a PortValueCombinedmode message, in
+    /// combination order
+    Combined {
+        port_id: u8,
+        values: Vec<CombinedValue>,
+    },
+}
+
+impl PortValues {
+    /// Decode the values in `msg`, if it is a port value message whose
+    /// formats are in the registry
+    pub fn decode(
+        msg: &NotificationMessage,
+        registry: &ValueFormatRegistry,
+    ) -> Option<Self> {
+        let values = match msg {
+            NotificationMessage::PortValueSingle(value) => {
+                value.process(registry).map(PortValues::Single)
+            }
+            NotificationMessage::PortValueCombinedmode(value) => {
+                value.process(registry).map(|values| PortValues::Combined {
+                    port_id: value.port_id,
+                    values,
+                })
+            }
+            _ => return None,
+        };
+        values
+            .map_err(|e| debug!("Port values not decoded: {}", e))
+            .ok()
+    }
+}
+
+/// A notification as received from the hub, with the result of parsing it
+#[derive(Clone, Debug)]
+pub struct HubNotification {
+    pub raw: Vec<u8>,
+    pub message: std::result::Result<NotificationMessage, Arc<Error>>,
+    /// Decoded values of a port value message, if the hub has reported
+    /// their formats
+    pub values: Option<PortValues>,
+}
+
+impl HubNotification {
+    /// Parse a notification without decoding any port values
+    pub fn new(raw: Vec<u8>) -> Self {
+        let message = NotificationMessage::parse(&raw).map_err(Arc::new);
+        Self {
+            raw,
+            message,
+            values: None,
+        }
+    }
+
+    /// Parse a notification, record any value formats it reports in
+    /// `registry` and decode its port values with what is recorded
+    pub fn with_registry(
+        raw: Vec<u8>,
+        registry: &mut ValueFormatRegistry,
+    ) -> Self {
+        let mut notification = Self::new(raw);
+        if let Ok(msg) = &notification.message {
+            registry.update(msg);
+            notification.values = PortValues::decode(msg, registry);
+        }
+        notification
+    }
+
+    /// Message type from the frame header, available even if the rest of
+    /// the message failed to parse
+    pub fn message_type(&self) -> Option<MessageType> {
+        let offset = match self.raw.first() {
+            Some(len) if len & 0x80 != 0 => 3,
+            Some(_) => 2,
+            None => return None,
+        };
+        self.raw.get(offset).copied().and_then(MessageType::from_u8)
+    }
+
+    /// Every port the message is about, see `NotificationMessage::port_ids`.
+    /// The ports of a port value message are all known once its values are
+    /// decoded; until then only the first is.
+    pub fn port_ids(&self) -> Vec<u8> {
+        if let Some(PortValues::Single(ports)) = &self.values {
+            let mut port_ids: Vec<u8> = ports.keys().copied().collect();
+            port_ids.sort_unstable();
+            return port_ids;
+        }
+        self.message
+            .as_ref()
+            .map_or_else(|_| Vec::new(), |msg| msg.port_ids())
+    }
+}
+
+/// Owns the background task. The channel closes for all receivers when the
+/// hub's notifications end, or when the dispatcher is dropped, which stops
+/// the task.
+///
+/// The dispatcher keeps the value formats of the hub's ports in a
+/// `ValueFormatRegistry`, so each hub connection should have its own.
+#[derive(Debug)]
+pub struct Dispatcher {
+    /// Taken away once the notifications end, so that only the task's
+    /// sender is left to close the channel
+    sender: Arc<Mutex<Option<broadcast::Sender<HubNotification>>>>,
+    registry: Arc<Mutex<ValueFormatRegistry>>,
+    task: JoinHandle<()>,
+}
+
+impl Dispatcher {
+    /// Start dispatching the frames from `frames`. Must be called from
+    /// within a tokio runtime.
+    pub fn spawn<S>(frames: S) -> Self
+    where
+        S: Stream<Item = Vec<u8>> + Send + 'static,
+    {
+        let (tx, _) = broadcast::channel(CHANNEL_CAPACITY);
+        let sender = Arc::new(Mutex::new(Some(tx.clone())));
+        let registry = Arc::new(Mutex::new(ValueFormatRegistry::new()));
+        let subscriptions = sender.clone();
+        let formats = registry.clone();
+        let task = tokio::spawn(async move {
+            futures::pin_mut!(frames);
+            while let Some(raw) = frames.next().await {
+                let notification = HubNotification::with_registry(
+                    raw,
+                    &mut formats.lock().unwrap(),
+                );
+                if let Err(e) = &notification.message {
+                    warn!("Failed to parse notification: {}", e);
+                }
+                // Nobody listening is fine
+                let _ = tx.send(notification);
+            }
+            subscriptions.lock().unwrap().take();
+        });
+        Self {
+            sender,
+            registry,
+            task,
+        }
+    }
+
+    /// Record a message sent to the hub. The hub doesn't repeat the
+    /// mode/dataset combinations it is given, so they are only known from
+    /// the combined mode setups sent to it.
+    pub fn sent(&self, msg: &NotificationMessage) {
+        self.registry.lock().unwrap().update(msg);
+    }
+
+    /// Snapshot of the value formats recorded so far
+    pub fn value_formats(&self) -> ValueFormatRegistry {
+        self.registry.lock().unwrap().clone()
+    }
+
+    /// Receive every notification from now on
+    pub fn subscribe(&self) -> NotificationReceiver {
+        let receiver = match &*self.sender.lock().unwrap() {
+            Some(sender) => sender.subscribe(),
+            // The notifications have ended, so start out closed
+            None => broadcast::channel(1).1,
+        };
+        NotificationReceiver {
+            receiver,
+            port: None,
+            message_type: None,
+        }
+    }
+}
+
+impl Drop for Dispatcher {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Subscription to a hub's notifications, optionally narrowed down by port
+/// and message type
+#[derive(Debug)]
+pub struct NotificationReceiver {
+    receiver: broadcast::Receiver<HubNotification>,
+    port: Option<u8>,
+    message_type: Option<MessageType>,
+}
+
+impl NotificationReceiver {
+    /// Only receive messages about the port with this id, see
+    /// `HubNotification::port_ids`
+    pub fn port(mut self, port_id: u8) -> Self {
+        self.port = Some(port_id);
+        self
+    }
+
+    /// Only receive messages of this type
+    pub fn message_type(mut self, message_type: MessageType) -> Self {
+        self.message_type = Some(message_type);
+        self
+    }
+
+    fn accepts(&self, notification: &HubNotification) -> bool {
+        self.port
+            .is_none_or(|port| notification.port_ids().contains(&port))
+            && self
+                .message_type
+                .is_none_or(|t| notification.message_type() == Some(t))
+    }
+
+    /// Next matching notification, or `None` once the dispatcher has
+    /// stopped. Notifications missed by falling behind are skipped.
+    pub async fn recv(&mut self) -> Option<HubNotification> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(notification) if self.accepts(&notification) => {
+                    return Some(notification)
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(n)) => {
+                    warn!("Notification receiver missed {} messages", n)
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    pub fn into_stream(self) -> NotificationStream<HubNotification> {
+        Box::pin(futures::stream::unfold(self, |mut receiver| async move {
+            receiver.recv().await.map(|n| (n, receiver))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::notifications::TypedValue;
+    use futures::channel::mpsc;
+
+    const ATTACHED: [u8; 15] = [
+        0x0f, 0x00, 0x04, 0x01, 0x01, 0x2e, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00,
+        0x00, 0x00, 0x10,
+    ];
+    const ACTION: [u8; 4] = [0x04, 0x00, 0x02, 0x30];
+    const BROKEN: [u8; 4] = [0x04, 0x00, 0x02, 0xff];
+    /// Feedback for ports 0 and 1
+    const FEEDBACK: [u8; 7] = [0x07, 0x00, 0x82, 0x00, 0x0a, 0x01, 0x0a];
+
+    fn spawn() -> (mpsc::UnboundedSender<Vec<u8>>, Dispatcher) {
+        let (transport, frames) = mpsc::unbounded();
+        (transport, Dispatcher::spawn(frames))
+    }
+
+    #[test]
+    fn notification_ports() {
+        // Attach event for port 1
+        let attached = HubNotification::new(ATTACHED.to_vec());
+        assert_eq!(attached.message_type(), Some(MessageType::HubAttachedIo));
+        assert_eq!(attached.port_ids(), [1]);
+
+        // Single value for port 0x32
+        let value = HubNotification::new(vec![0x05, 0x00, 0x45, 0x32, 0x07]);
+        assert_eq!(value.port_ids(), [0x32]);
+        assert_eq!(value.values, None);
+
+        let feedback = HubNotification::new(FEEDBACK.to_vec());
+        assert_eq!(feedback.port_ids(), [0, 1]);
+
+        // Hub actions are not about a port
+        let action = HubNotification::new(ACTION.to_vec());
+        assert_eq!(action.message_type(), Some(MessageType::HubActions));
+        assert!(action.port_ids().is_empty());
+
+        // The type is read from the header even if the body is garbage
+        let broken = HubNotification::new(BROKEN.to_vec());
+        assert!(broken.message.is_err());
+        assert_eq!(broken.message_type(), Some(MessageType::HubActions));
+        assert_eq!(broken.raw, BROKEN);
+    }
+
+    #[tokio::test]
+    async fn fan_out() {
+        let (transport, dispatcher) = spawn();
+        let mut first = dispatcher.subscribe();
+        let mut second = dispatcher.subscribe();
+        transport.unbounded_send(ATTACHED.to_vec()).unwrap();
+        transport.unbounded_send(ACTION.to_vec()).unwrap();
+
+        for receiver in [&mut first, &mut second] {
+            assert_eq!(receiver.recv().await.unwrap().raw, ATTACHED);
+            let action = receiver.recv().await.unwrap();
+            assert_eq!(action.raw, ACTION);
+            assert_eq!(
+                action.message.unwrap(),
+                NotificationMessage::HubActions(
+                    crate::notifications::HubAction::HubWillSwitchOff
+                )
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn filters() {
+        let (transport, dispatcher) = spawn();
+        let mut port = dispatcher.subscribe().port(1);
+        let mut actions =
+            dispatcher.subscribe().message_type(MessageType::HubActions);
+        let mut both = dispatcher
+            .subscribe()
+            .port(0)
+            .message_type(MessageType::PortOutputCommandFeedback);
+        for frame in [&ATTACHED[..], &ACTION, &FEEDBACK, &BROKEN] {
+            transport.unbounded_send(frame.to_vec()).unwrap();
+        }
+        drop(transport);
+
+        assert_eq!(port.recv().await.unwrap().raw, ATTACHED);
+        // Feedback covers port 1 as well as port 0
+        assert_eq!(port.recv().await.unwrap().raw, FEEDBACK);
+        assert!(port.recv().await.is_none());
+
+        // The type of a frame that fails to parse is still known
+        assert_eq!(actions.recv().await.unwrap().raw, ACTION);
+        assert_eq!(actions.recv().await.unwrap().raw, BROKEN);
+        assert!(actions.recv().await.is_none());
+
+        assert_eq!(both.recv().await.unwrap().raw, FEEDBACK);
+        assert!(both.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn parse_errors_shared() {
+        let (transport, dispatcher) = spawn();
+        let mut first = dispatcher.subscribe();
+        let mut second = dispatcher.subscribe();
+        transport.unbounded_send(BROKEN.to_vec()).unwrap();
+
+        let first = first.recv().await.unwrap();
+        let second = second.recv().await.unwrap();
+        let (Err(first), Err(second)) = (first.message, second.message) else {
+            panic!("parsed a broken frame");
+        };
+        // Parsed once, and the error shared rather than copied
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(matches!(*first, Error::NoneError(_)), "{:?}", first);
+    }
+
+    #[tokio::test]
+    async fn port_values_across_ports() {
+        let (transport, dispatcher) = spawn();
+        let mut port = dispatcher.subscribe().port(1);
+        // Port 0 reports an i32 in mode 2 and port 1 a u8 in mode 0
+        for frame in [
+            &[10, 0, 0x47, 0, 2, 1, 0, 0, 0, 1][..],
+            &[10, 0, 0x44, 0, 2, 0x80, 1, 2, 4, 0],
+            &[10, 0, 0x47, 1, 0, 1, 0, 0, 0, 1],
+            &[10, 0, 0x44, 1, 0, 0x80, 1, 0, 3, 0],
+            &[10, 0, 0x45, 0, 0x68, 0x01, 0, 0, 1, 7],
+        ] {
+            transport.unbounded_send(frame.to_vec()).unwrap();
+        }
+
+        let mut values = None;
+        while let Some(notification) = port.recv().await {
+            if notification.message_type() == Some(MessageType::PortValueSingle)
+            {
+                values = Some(notification);
+                break;
+            }
+        }
+        let values = values.unwrap();
+        assert_eq!(values.port_ids(), [0, 1]);
+        let Some(PortValues::Single(ports)) = values.values else {
+            panic!("values not decoded");
+        };
+        assert_eq!(ports[&0], [TypedValue::Bits32(360)]);
+        assert_eq!(ports[&1], [TypedValue::Bits8(7)]);
+        assert_eq!(
+            dispatcher
+                .value_formats()
+                .current_value_format(1)
+                .map(|f| { f.number_of_datasets }),
+            Some(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn ends_with_transport() {
+        let (transport, dispatcher) = spawn();
+        let mut stream = dispatcher.subscribe().into_stream();
+        transport.unbounded_send(ACTION.to_vec()).unwrap();
+        drop(transport);
+
+        assert_eq!(stream.next().await.unwrap().raw, ACTION);
+        assert!(stream.next().await.is_none());
+        // Later subscribers find the channel already closed
+        assert!(dispatcher.subscribe().recv().await.is_none());
+    }
+}
@@ -2,6 +2,8 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use crate::consts::MessageType;
+use crate::notifications::ErrorCode;
 use btleplug::api::ParseBDAddrError;
 use std::fmt::Display;
 
@@ -21,6 +23,17 @@ pub enum Error {
     NotImplementedError(String),
     #[error("Hub error: {0}")]
     HubError(String),
+    #[error("IO error")]
+    IoError(#[from] std::io::Error),
+    /// The hub replied to a request with a generic error message. Only
+    /// hub methods that wait for a reply, such as property requests, return
+    /// this; errors for commands that are just sent, such as device output
+    /// commands, arrive as `GenericErrorMessages` notifications.
+    #[error("Hub rejected {command:?} command: {code:?}")]
+    Protocol {
+        command: MessageType,
+        code: ErrorCode,
+    },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -37,6 +50,6 @@ impl<T> OptionContext<T> for Option<T> {
 
 impl<T> OptionContext<T> for Result<T> {
     fn context<D: Display>(self, _ctx: D) -> Result<T> {
-        self.map_err(Error::from)
+        self
     }
 }
@@ -4,25 +4,54 @@
 
 //! Specific implementations for each of the supported hubs.
 
+use crate::consts::{
+    AlertPayload, DeviceType, HubPropertyOperation, HubPropertyReference,
+    HubType, MessageType,
+};
 use crate::devices::{self, Device};
-use crate::error::{OptionContext, Result};
-use btleplug::api::{Characteristic, Peripheral as _, WriteType};
+use crate::dispatcher::{Dispatcher, NotificationReceiver, PortValues};
+use crate::error::{Error, OptionContext, Result};
+use crate::notifications::{
+    self, AlertOperation, AlertType, AttachedIo, CombinedValue, HubAction,
+    HubAlert, HubBatteryType, HubProperty, HubPropertyValue, IoAttachEvent,
+    IoTypeId, ModeInformationRequest, ModeInformationType, NetworkCommand,
+    NetworkFamily, NetworkSubFamily, NotificationMessage,
+    PortModeInformationType, PortModeInformationValue, TypedValue,
+    ValueFormatType,
+};
+use crate::transport::{BleTransport, Transport};
+use btleplug::api::{Characteristic, Peripheral as _};
 use btleplug::platform::Peripheral;
+use futures::stream::{Stream, StreamExt};
+use log::warn;
+use num_traits::FromPrimitive;
 use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// How long `Hub::port` waits for a device to be attached
+const ATTACH_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long to wait for the hub to answer a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+/// Number of IO events an `io_events` stream may fall behind before it
+/// starts missing them
+const IO_EVENT_CAPACITY: usize = 64;
+
+/// Stream of values picked out of a hub's notifications
+pub type NotificationStream<T> = Pin<Box<dyn Stream<Item = T> + Send>>;
 
 /// Trait describing a generic hub.
 #[async_trait::async_trait]
-pub trait Hub {
+pub trait Hub: Send + Sync {
     async fn name(&self) -> Result<String>;
     async fn disconnect(&self) -> Result<()>;
     async fn is_connected(&self) -> Result<bool>;
     // The init function cannot be a trait method until we have GAT :(
     //fn init(peripheral: P);
-    async fn properties(&self) -> &HubProperties;
-
-    // async fn port_map(&self) -> &PortMap {
-    //     &self.properties().await.port_map
-    // }
+    /// Snapshot of the hub's properties, as last reported by the hub
+    async fn properties(&self) -> HubProperties;
 
     // cannot provide a default implementation without access to the
     // Peripheral trait from here
@@ -30,20 +59,286 @@ pub trait Hub {
 
     // fn send(&self, msg: NotificationMessage) -> Result<()>;
 
-    async fn subscribe(&self, char: Characteristic) -> Result<()>;
+    /// Subscribe to every notification the hub sends from now on, parsed
+    /// once by the hub's dispatcher
+    fn notifications(&self) -> NotificationReceiver;
 
     /// Ideally the vec should be sorted somehow
     async fn attached_io(&self) -> Vec<ConnectedIo>;
 
-    // fn process_io_event(&mut self, _evt: AttachedIo);
+    /// Stream of devices being attached to and detached from the hub's
+    /// ports, each sent once the hub has recorded it in `attached_io`. Ends
+    /// along with the hub's notifications.
+    async fn io_events(&self) -> Result<NotificationStream<IoEvent>>;
+
+    /// Wait until a device of the given type is attached to `port`,
+    /// returning straight away if it already is
+    async fn wait_for_device(
+        &self,
+        port: Port,
+        device_type: DeviceType,
+    ) -> Result<ConnectedIo> {
+        // Listen before looking so an attach in between isn't missed
+        let mut events = self.io_events().await?;
+        let is_wanted = |io: &ConnectedIo| {
+            io.port == port && io.device_type() == device_type
+        };
+        if let Some(io) = self.attached_io().await.into_iter().find(is_wanted) {
+            return Ok(io);
+        }
+        while let Some(event) = events.next().await {
+            match event {
+                IoEvent::Attached(io) if is_wanted(&io) => return Ok(io),
+                _ => {}
+            }
+        }
+        Err(Error::HubError(
+            "Notifications ended before the device was attached".to_string(),
+        ))
+    }
 
     async fn port(&self, port_id: Port) -> Result<Box<dyn Device>>;
+
+    /// Value format of one of a port's modes, asking the hub for it unless
+    /// it has already been reported
+    async fn value_format(
+        &self,
+        port: Port,
+        mode: u8,
+    ) -> Result<ValueFormatType>;
+
+    /// Stream of the values a port reports in its input mode, decoded with
+    /// the mode's value format. Values are left out while the format isn't
+    /// known, so get it with `value_format` before setting up the mode.
+    async fn port_values(
+        &self,
+        port: Port,
+    ) -> Result<NotificationStream<Vec<TypedValue>>>;
+
+    /// Stream of the values a port reports in combined mode, decoded with
+    /// the mode/dataset combination set up through `send_raw` and the value
+    /// formats of its modes
+    async fn combined_values(
+        &self,
+        port: Port,
+    ) -> Result<NotificationStream<Vec<CombinedValue>>>;
+
+    /// Network family and subfamily of the hub, i.e. the colour and number
+    /// of flashes its LED shows to tell it apart from identical hubs
+    async fn network_family(&self)
+        -> Result<(NetworkFamily, NetworkSubFamily)>;
+
+    async fn set_network_family(
+        &self,
+        family: NetworkFamily,
+        subfamily: NetworkSubFamily,
+    ) -> Result<()>;
+
+    /// Send a hub property operation, e.g. to set a property or enable
+    /// updates for it. The hub's properties only change once the hub
+    /// reports the new value.
+    async fn hub_property(&self, property: HubProperty) -> Result<()>;
+
+    /// Request the current value of a property and wait for the reply,
+    /// which is also recorded in the hub's properties
+    async fn request_property(
+        &self,
+        reference: HubPropertyReference,
+    ) -> Result<HubPropertyValue>;
+
+    /// Ask the hub to switch itself off. The hub announces it with a
+    /// `HubWillSwitchOff` action event first.
+    async fn switch_off(&self) -> Result<()> {
+        let msg = NotificationMessage::HubActions(HubAction::SwitchOffHub);
+        self.send_raw(&msg.try_serialise()?).await
+    }
+
+    /// Ask the hub to close the connection, as opposed to `disconnect`
+    /// which drops it from our end
+    async fn request_disconnect(&self) -> Result<()> {
+        let msg = NotificationMessage::HubActions(HubAction::Disconnect);
+        self.send_raw(&msg.try_serialise()?).await
+    }
+
+    /// Switch the supply voltage on the hub's ports on or off
+    async fn set_vcc_port(&self, on: bool) -> Result<()> {
+        let action = if on {
+            HubAction::VccPortControlOn
+        } else {
+            HubAction::VccPortControlOff
+        };
+        let msg = NotificationMessage::HubActions(action);
+        self.send_raw(&msg.try_serialise()?).await
+    }
+
+    /// Show or clear the busy indication on the hub's LED
+    async fn set_busy_indication(&self, on: bool) -> Result<()> {
+        let action = if on {
+            HubAction::ActivateBusyIndication
+        } else {
+            HubAction::ResetBusyIndication
+        };
+        let msg = NotificationMessage::HubActions(action);
+        self.send_raw(&msg.try_serialise()?).await
+    }
+
+    /// Warnings from the hub that it is about to switch off, disconnect or
+    /// go into boot mode
+    async fn action_events(&self) -> Result<NotificationStream<HubAction>>;
+
+    /// Status changes of every alert type. Updates are enabled for all of
+    /// them, so the hub reports the current status of each and then every
+    /// change.
+    async fn alerts(
+        &self,
+    ) -> Result<NotificationStream<(AlertType, AlertPayload)>>;
+
+    /// Request the current status of one alert and wait for the reply
+    async fn alert_status(&self, alert_type: AlertType)
+        -> Result<AlertPayload>;
+
+    /// Rename the hub, then read the name back to check that the hub has
+    /// taken it
+    async fn set_name(&self, name: &str) -> Result<()> {
+        let value = HubPropertyValue::advertising_name(name)?;
+        self.hub_property(HubProperty::set(value)).await?;
+        match self
+            .request_property(HubPropertyReference::AdvertisingName)
+            .await?
+        {
+            HubPropertyValue::AdvertisingName(n) if n == name.as_bytes() => {
+                Ok(())
+            }
+            HubPropertyValue::AdvertisingName(n) => {
+                Err(Error::HubError(format!(
+                    "Hub reports name `{}` after renaming to `{}`",
+                    String::from_utf8_lossy(&n),
+                    name
+                )))
+            }
+            v => Err(unexpected_property(v)),
+        }
+    }
+
+    /// Battery charge in percent
+    async fn battery_voltage(&self) -> Result<u8> {
+        match self
+            .request_property(HubPropertyReference::BatteryVoltage)
+            .await?
+        {
+            HubPropertyValue::BatteryVoltage(v) => Ok(v),
+            v => Err(unexpected_property(v)),
+        }
+    }
+
+    async fn battery_type(&self) -> Result<HubBatteryType> {
+        match self
+            .request_property(HubPropertyReference::BatteryType)
+            .await?
+        {
+            HubPropertyValue::BatteryType(t) => Ok(t),
+            v => Err(unexpected_property(v)),
+        }
+    }
+
+    async fn fw_version(&self) -> Result<notifications::VersionNumber> {
+        match self
+            .request_property(HubPropertyReference::FwVersion)
+            .await?
+        {
+            HubPropertyValue::FwVersion(v) => version_number(v),
+            v => Err(unexpected_property(v)),
+        }
+    }
+
+    async fn hw_version(&self) -> Result<notifications::VersionNumber> {
+        match self
+            .request_property(HubPropertyReference::HwVersion)
+            .await?
+        {
+            HubPropertyValue::HwVersion(v) => version_number(v),
+            v => Err(unexpected_property(v)),
+        }
+    }
+
+    /// Signal strength in dBm
+    async fn rssi(&self) -> Result<i8> {
+        match self.request_property(HubPropertyReference::Rssi).await? {
+            HubPropertyValue::Rssi(r) => Ok(r),
+            v => Err(unexpected_property(v)),
+        }
+    }
+
+    async fn manufacturer_name(&self) -> Result<String> {
+        match self
+            .request_property(HubPropertyReference::ManufacturerName)
+            .await?
+        {
+            HubPropertyValue::ManufacturerName(n) => {
+                Ok(String::from_utf8_lossy(&n).into_owned())
+            }
+            v => Err(unexpected_property(v)),
+        }
+    }
+
+    async fn radio_firmware_version(&self) -> Result<String> {
+        match self
+            .request_property(HubPropertyReference::RadioFirmwareVersion)
+            .await?
+        {
+            HubPropertyValue::RadioFirmwareVersion(n) => {
+                Ok(String::from_utf8_lossy(&n).into_owned())
+            }
+            v => Err(unexpected_property(v)),
+        }
+    }
+
+    /// LEGO Wireless Protocol version, BCD encoded, e.g. 0x0300 for 3.0
+    async fn lwp_version(&self) -> Result<u16> {
+        match self
+            .request_property(HubPropertyReference::LegoWirelessProtocolVersion)
+            .await?
+        {
+            HubPropertyValue::LegoWirelessProtocolVersion(v) => Ok(v),
+            v => Err(unexpected_property(v)),
+        }
+    }
+
+    async fn system_type_id(&self) -> Result<u8> {
+        match self
+            .request_property(HubPropertyReference::SystemTypeId)
+            .await?
+        {
+            HubPropertyValue::SystemTypeId(t) => Ok(t),
+            v => Err(unexpected_property(v)),
+        }
+    }
+
+    async fn primary_mac_address(&self) -> Result<[u8; 6]> {
+        match self
+            .request_property(HubPropertyReference::PrimaryMacAddress)
+            .await?
+        {
+            HubPropertyValue::PrimaryMacAddress(mac) => Ok(mac),
+            v => Err(unexpected_property(v)),
+        }
+    }
+}
+
+fn unexpected_property(value: HubPropertyValue) -> Error {
+    Error::HubError(format!("Unexpected property value {:?}", value))
+}
+
+/// Versions are sent as property values in the same layout as in
+/// attach events
+fn version_number(value: i32) -> Result<notifications::VersionNumber> {
+    notifications::VersionNumber::parse(value.to_le_bytes().iter())
 }
 
 pub type VersionNumber = u8;
 
 /// Propeties of a hub
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct HubProperties {
     /// Friendly name, set via the PoweredUp or Control+ apps
     pub name: String,
@@ -57,12 +352,166 @@ pub struct HubProperties {
     pub battery_level: usize,
     /// BLE signal strength
     pub rssi: i16,
+    /// Type of battery fitted, if the hub has reported it
+    pub battery_type: Option<HubBatteryType>,
+    pub manufacturer_name: String,
+    /// Firmware revision of the hub's BLE radio
+    pub radio_firmware_version: String,
+    /// LEGO Wireless Protocol version, BCD encoded
+    pub lwp_version: u16,
+    pub system_type_id: u8,
     /// Mapping from port type to port ID. Internally (to the hub) each
     /// port has a hardcoded identifier
     pub port_map: PortMap,
 }
 
-pub type PortMap = HashMap<Port, u8>;
+impl HubProperties {
+    /// Record a property value reported by the hub
+    pub fn update(&mut self, value: &HubPropertyValue) {
+        use HubPropertyValue::*;
+        match value {
+            AdvertisingName(name) => {
+                self.name = String::from_utf8_lossy(name).into_owned()
+            }
+            FwVersion(v) => {
+                if let Ok(v) = version_number(*v) {
+                    self.fw_version = v.to_string();
+                }
+            }
+            HwVersion(v) => {
+                if let Ok(v) = version_number(*v) {
+                    self.hw_version = v.to_string();
+                }
+            }
+            Rssi(rssi) => self.rssi = *rssi as i16,
+            BatteryVoltage(level) => self.battery_level = *level as usize,
+            BatteryType(t) => self.battery_type = Some(*t),
+            ManufacturerName(name) => {
+                self.manufacturer_name =
+                    String::from_utf8_lossy(name).into_owned()
+            }
+            RadioFirmwareVersion(v) => {
+                self.radio_firmware_version =
+                    String::from_utf8_lossy(v).into_owned()
+            }
+            LegoWirelessProtocolVersion(v) => self.lwp_version = *v,
+            SystemTypeId(t) => self.system_type_id = *t,
+            PrimaryMacAddress(mac) => {
+                self.mac_address = mac
+                    .iter()
+                    .map(|b| format!("{:02X}", b))
+                    .collect::<Vec<_>>()
+                    .join(":")
+            }
+            Button(_)
+            | HwNetworkId(_)
+            | SecondaryMacAddress
+            | HardwareNetworkFamily(_) => {}
+        }
+    }
+}
+
+/// The ports of a hub and their port ids. Starts out with the named ports
+/// of the hub type and learns unnamed and virtual ports from attach events.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PortMap {
+    ports: HashMap<Port, u8>,
+}
+
+impl PortMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The named ports of a hub type
+    pub fn for_hub_type(hub_type: HubType) -> Self {
+        use Port::*;
+        let ports: &[(Port, u8)] = match hub_type {
+            HubType::TechnicMediumHub => &[
+                (A, 0x00),
+                (B, 0x01),
+                (C, 0x02),
+                (D, 0x03),
+                (HubLed, 0x32),
+                (CurrentSensor, 0x3b),
+                (VoltageSensor, 0x3c),
+                (Accelerometer, 0x61),
+                (GyroSensor, 0x62),
+                (TiltSensor, 0x63),
+                (GestureSensor, 0x64),
+            ],
+            HubType::MoveHub => &[
+                (A, 0x00),
+                (B, 0x01),
+                (C, 0x02),
+                (D, 0x03),
+                (HubLed, 0x32),
+                (TiltSensor, 0x3a),
+                (CurrentSensor, 0x3b),
+                (VoltageSensor, 0x3c),
+            ],
+            HubType::Hub => &[
+                (A, 0x00),
+                (B, 0x01),
+                (HubLed, 0x32),
+                (CurrentSensor, 0x3b),
+                (VoltageSensor, 0x3c),
+            ],
+            HubType::RemoteControl => {
+                &[(A, 0x00), (B, 0x01), (HubLed, 0x34), (VoltageSensor, 0x3b)]
+            }
+            HubType::DuploTrainBase => {
+                &[(A, 0x00), (HubLed, 0x11), (VoltageSensor, 0x14)]
+            }
+            HubType::Unknown | HubType::Wedo2SmartHub | HubType::Mario => &[],
+        };
+        Self {
+            ports: ports.iter().copied().collect(),
+        }
+    }
+
+    pub fn insert(&mut self, port: Port, port_id: u8) {
+        self.ports.insert(port, port_id);
+    }
+
+    /// Id of a port on this hub. Virtual and unnamed ports carry their id.
+    pub fn id(&self, port: Port) -> Option<u8> {
+        match port {
+            Port::Virtual(id) | Port::Other(id) => Some(id),
+            _ => self.ports.get(&port).copied(),
+        }
+    }
+
+    /// The port with the given id on this hub
+    pub fn port(&self, port_id: u8) -> Port {
+        self.ports
+            .iter()
+            .find(|(_, id)| **id == port_id)
+            .map_or(Port::Other(port_id), |(port, _)| *port)
+    }
+
+    /// Every port known on this hub
+    pub fn ports(&self) -> impl Iterator<Item = (Port, u8)> + '_ {
+        self.ports.iter().map(|(port, id)| (*port, *id))
+    }
+
+    /// Add the ports that an attach event reveals, and drop virtual ports
+    /// when they are detached
+    pub fn learn(&mut self, event: &IoEvent) {
+        match event {
+            IoEvent::Attached(io) => {
+                self.ports.entry(io.port).or_insert(io.port_id);
+            }
+            IoEvent::Detached {
+                port: port @ Port::Virtual(_),
+                ..
+            } => {
+                self.ports.remove(port);
+            }
+            IoEvent::Detached { .. } => {}
+        }
+    }
+}
 
 /// Ports supported by any hub
 #[non_exhaustive]
@@ -83,70 +532,148 @@ pub enum Port {
     GyroSensor,
     TiltSensor,
     GestureSensor,
+    /// Virtual port combining two ports, created at runtime
     Virtual(u8),
+    /// Port without a name, such as the internal ports a hub reports
+    /// beyond the named ones
+    Other(u8),
 }
 
 impl Port {
-    /// Returns the ID of the port
-    pub fn id(&self) -> u8 {
-        todo!()
+    /// Returns the ID of the port if it is the same on every hub, as it is
+    /// for ports A-D and for the ports that carry their ID. Internal ports
+    /// sit at different IDs on different hub types, so look those up in the
+    /// hub's `PortMap`.
+    pub fn id(&self) -> Option<u8> {
+        match self {
+            Port::A => Some(0x00),
+            Port::B => Some(0x01),
+            Port::C => Some(0x02),
+            Port::D => Some(0x03),
+            Port::Virtual(id) | Port::Other(id) => Some(*id),
+            _ => None,
+        }
     }
 }
 
 /// Struct representing a device connected to a port
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ConnectedIo {
-    /// Name/type of device
+    /// Port the device is attached to
     pub port: Port,
-    /// Internal numeric ID of the device
+    /// Internal numeric ID of the port
     pub port_id: u8,
-    /// Device firmware revision
-    pub fw_rev: VersionNumber,
-    /// Device hardware revision
-    pub hw_rev: VersionNumber,
+    /// Type of the attached device
+    pub io_type_id: IoTypeId,
+    /// Device firmware revision, not reported for virtual ports
+    pub fw_rev: Option<notifications::VersionNumber>,
+    /// Device hardware revision, not reported for virtual ports
+    pub hw_rev: Option<notifications::VersionNumber>,
+    /// Port ids of the two ports combined into a virtual port
+    pub virtual_ports: Option<(u8, u8)>,
+}
+
+impl ConnectedIo {
+    pub fn device_type(&self) -> DeviceType {
+        DeviceType::from_u16(self.io_type_id as u16)
+            .unwrap_or(DeviceType::Unknown)
+    }
+}
+
+/// A device being attached to or detached from a port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IoEvent {
+    Attached(ConnectedIo),
+    Detached { port: Port, port_id: u8 },
+}
+
+impl IoEvent {
+    /// Interpret an attach event using the hub's port map
+    pub fn new(event: &AttachedIo, port_map: &PortMap) -> Self {
+        let port_id = event.port;
+        let port = port_map.port(port_id);
+        match event.event {
+            IoAttachEvent::DetachedIo => IoEvent::Detached { port, port_id },
+            IoAttachEvent::AttachedIo {
+                io_type_id,
+                hw_rev,
+                fw_rev,
+            } => IoEvent::Attached(ConnectedIo {
+                port,
+                port_id,
+                io_type_id,
+                fw_rev: Some(fw_rev),
+                hw_rev: Some(hw_rev),
+                virtual_ports: None,
+            }),
+            IoAttachEvent::AttachedVirtualIo {
+                io_type_id,
+                port_a,
+                port_b,
+            } => IoEvent::Attached(ConnectedIo {
+                port: match port {
+                    Port::Other(id) => Port::Virtual(id),
+                    port => port,
+                },
+                port_id,
+                io_type_id,
+                fw_rev: None,
+                hw_rev: None,
+                virtual_ports: Some((port_a, port_b)),
+            }),
+        }
+    }
+
+    /// Record the event in a map of attached devices by port id
+    pub fn apply(&self, connected_io: &mut HashMap<u8, ConnectedIo>) {
+        match self {
+            IoEvent::Attached(io) => {
+                connected_io.insert(io.port_id, io.clone());
+            }
+            IoEvent::Detached { port_id, .. } => {
+                connected_io.remove(port_id);
+            }
+        }
+    }
 }
 
 /// Definition for the TechnicMediumHub
 pub struct TechnicHub {
-    peripheral: Peripheral,
-    lpf_characteristic: Characteristic,
-    properties: HubProperties,
-    connected_io: HashMap<u8, ConnectedIo>,
+    transport: Arc<dyn Transport>,
+    properties: Arc<Mutex<HubProperties>>,
+    connected_io: Arc<Mutex<HashMap<u8, ConnectedIo>>>,
+    /// IO events as the tracker applies them to `connected_io`. Taken away
+    /// when the tracker stops, which ends the `io_events` streams.
+    io_events: Arc<Mutex<Option<broadcast::Sender<IoEvent>>>>,
+    dispatcher: Dispatcher,
 }
 
 #[async_trait::async_trait]
 impl Hub for TechnicHub {
     async fn name(&self) -> Result<String> {
-        Ok(self
-            .peripheral
-            .properties()
-            .await?
-            .context("No properties found for hub")?
-            .local_name
-            .unwrap_or_default())
+        Ok(self.properties.lock().unwrap().name.clone())
     }
 
     async fn disconnect(&self) -> Result<()> {
         if self.is_connected().await? {
-            self.peripheral.disconnect().await?;
+            self.transport.disconnect().await?;
         }
         Ok(())
     }
 
     async fn is_connected(&self) -> Result<bool> {
-        Ok(self.peripheral.is_connected().await?)
+        self.transport.is_connected().await
     }
 
-    async fn properties(&self) -> &HubProperties {
-        &self.properties
+    async fn properties(&self) -> HubProperties {
+        self.properties.lock().unwrap().clone()
     }
 
     async fn send_raw(&self, msg: &[u8]) -> Result<()> {
-        let write_type = WriteType::WithoutResponse;
-        Ok(self
-            .peripheral
-            .write(&self.lpf_characteristic, msg, write_type)
-            .await?)
+        if let Ok(msg) = NotificationMessage::parse(msg) {
+            self.dispatcher.sent(&msg);
+        }
+        self.transport.send(msg).await
     }
 
     // fn send(&self, msg: NotificationMessage) -> Result<()> {
@@ -155,65 +682,254 @@ impl Hub for TechnicHub {
     //     Ok(())
     // }
 
-    async fn subscribe(&self, char: Characteristic) -> Result<()> {
-        Ok(self.peripheral.subscribe(&char).await?)
+    fn notifications(&self) -> NotificationReceiver {
+        self.dispatcher.subscribe()
     }
 
     async fn attached_io(&self) -> Vec<ConnectedIo> {
-        let mut ret = Vec::with_capacity(self.connected_io.len());
-        for (_k, v) in self.connected_io.iter() {
-            ret.push(v.clone());
-        }
+        let mut ret: Vec<ConnectedIo> = self
+            .connected_io
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
 
         ret.sort_by_key(|x| x.port_id);
 
         ret
     }
 
-    // fn process_io_event(&mut self, evt: AttachedIo) {
-    //     match evt.event {
-    //         IoAttachEvent::AttachedIo { hw_rev, fw_rev } => {
-    //             if let Some(port) = self.port_from_id(evt.port) {
-    //                 let io = ConnectedIo {
-    //                     port_id: evt.port,
-    //                     port,
-    //                     fw_rev,
-    //                     hw_rev,
-    //                 };
-    //                 self.connected_io.insert(evt.port, io);
-    //             }
-    //         }
-    //         IoAttachEvent::DetachedIo { io_type_id: _ } => {}
-    //         IoAttachEvent::AttachedVirtualIo {
-    //             port_a: _,
-    //             port_b: _,
-    //         } => {}
-    //     }
-    // }
+    async fn io_events(&self) -> Result<NotificationStream<IoEvent>> {
+        let receiver = match &*self.io_events.lock().unwrap() {
+            Some(sender) => sender.subscribe(),
+            // The tracker has stopped, so start out closed
+            None => broadcast::channel(1).1,
+        };
+        Ok(Box::pin(futures::stream::unfold(
+            receiver,
+            |mut receiver| async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok(event) => return Some((event, receiver)),
+                        Err(RecvError::Lagged(n)) => {
+                            warn!("IO event stream missed {} events", n)
+                        }
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            },
+        )))
+    }
 
     async fn port(&self, port_id: Port) -> Result<Box<dyn Device>> {
-        let port =
-            *self.properties.port_map.get(&port_id).ok_or_else(|| {
-                crate::Error::NoneError(format!(
-                    "Port type `{port_id:?}` not supported"
-                ))
-            })?;
-        Ok(match port_id {
-            Port::HubLed => Box::new(devices::HubLED::new(
-                self.peripheral.clone(),
-                self.lpf_characteristic.clone(),
-                port,
-            )),
-            Port::A | Port::B | Port::C | Port::D => {
-                Box::new(devices::Motor::new(
-                    self.peripheral.clone(),
-                    self.lpf_characteristic.clone(),
-                    port_id,
-                    port,
-                ))
+        let port = self.port_id(port_id)?;
+
+        // The hub reports its devices shortly after connecting, so give a
+        // device that isn't known yet a moment to turn up
+        let mut events = self.io_events().await?;
+        let attached = self.connected_io.lock().unwrap().get(&port).cloned();
+        let io = match attached {
+            Some(io) => Some(io),
+            None => tokio::time::timeout(ATTACH_TIMEOUT, async {
+                while let Some(event) = events.next().await {
+                    match event {
+                        IoEvent::Attached(io) if io.port_id == port => {
+                            return Some(io)
+                        }
+                        _ => {}
+                    }
+                }
+                None
+            })
+            .await
+            .ok()
+            .flatten(),
+        };
+        let io = io.ok_or_else(|| {
+            crate::Error::NoneError(format!(
+                "No device attached to port `{port_id:?}`"
+            ))
+        })?;
+
+        devices::create_device(
+            io.io_type_id,
+            self.transport.clone(),
+            port_id,
+            port,
+        )
+    }
+
+    async fn value_format(
+        &self,
+        port: Port,
+        mode: u8,
+    ) -> Result<ValueFormatType> {
+        let port_id = self.port_id(port)?;
+        if let Some(format) =
+            self.dispatcher.value_formats().value_format(port_id, mode)
+        {
+            return Ok(format);
+        }
+        let msg = NotificationMessage::PortModeInformationRequest(
+            ModeInformationRequest {
+                port_id,
+                mode,
+                information_type: ModeInformationType::ValueFormat,
+            },
+        );
+        self.request(msg, |reply| match reply {
+            NotificationMessage::PortModeInformation(
+                PortModeInformationValue {
+                    port_id: p,
+                    mode: m,
+                    information_type:
+                        PortModeInformationType::ValueFormat(format),
+                },
+            ) if p == port_id && m == mode => Some(format),
+            _ => None,
+        })
+        .await
+    }
+
+    async fn port_values(
+        &self,
+        port: Port,
+    ) -> Result<NotificationStream<Vec<TypedValue>>> {
+        let port_id = self.port_id(port)?;
+        let notifications = self
+            .notifications()
+            .port(port_id)
+            .message_type(MessageType::PortValueSingle)
+            .into_stream();
+        Ok(Box::pin(notifications.filter_map(move |notification| {
+            futures::future::ready(match notification.values {
+                Some(PortValues::Single(mut ports)) => ports.remove(&port_id),
+                _ => None,
+            })
+        })))
+    }
+
+    async fn combined_values(
+        &self,
+        port: Port,
+    ) -> Result<NotificationStream<Vec<CombinedValue>>> {
+        let port_id = self.port_id(port)?;
+        let notifications = self.notifications().port(port_id).into_stream();
+        Ok(Box::pin(notifications.filter_map(|notification| {
+            futures::future::ready(match notification.values {
+                Some(PortValues::Combined { values, .. }) => Some(values),
+                _ => None,
+            })
+        })))
+    }
+
+    async fn network_family(
+        &self,
+    ) -> Result<(NetworkFamily, NetworkSubFamily)> {
+        let msg = NotificationMessage::HwNetworkCommands(
+            NetworkCommand::GetExtendedFamily,
+        );
+        self.request(msg, |reply| match reply {
+            NotificationMessage::HwNetworkCommands(
+                NetworkCommand::ExtendedFamily { family, subfamily },
+            ) => Some((family, subfamily)),
+            _ => None,
+        })
+        .await
+    }
+
+    async fn set_network_family(
+        &self,
+        family: NetworkFamily,
+        subfamily: NetworkSubFamily,
+    ) -> Result<()> {
+        let msg = NotificationMessage::HwNetworkCommands(
+            NetworkCommand::ExtendedFamilySet { family, subfamily },
+        );
+        self.send_raw(&msg.try_serialise()?).await
+    }
+
+    async fn action_events(&self) -> Result<NotificationStream<HubAction>> {
+        self.notification_stream(|msg| match msg {
+            NotificationMessage::HubActions(action) if action.is_upstream() => {
+                Some(action)
             }
-            _ => todo!(),
+            _ => None,
+        })
+        .await
+    }
+
+    async fn alerts(
+        &self,
+    ) -> Result<NotificationStream<(AlertType, AlertPayload)>> {
+        // Listen before enabling updates so the initial status isn't missed
+        let stream = self
+            .notification_stream(|msg| match msg {
+                NotificationMessage::HubAlerts(HubAlert {
+                    alert_type,
+                    operation: AlertOperation::Update,
+                    payload: Some(payload),
+                }) => Some((alert_type, payload)),
+                _ => None,
+            })
+            .await?;
+        for alert_type in [
+            AlertType::LowVoltage,
+            AlertType::HighCurrent,
+            AlertType::LowSignalStrength,
+            AlertType::OverPowerCondition,
+        ] {
+            let msg = NotificationMessage::HubAlerts(HubAlert::enable_updates(
+                alert_type,
+            ));
+            self.send_raw(&msg.try_serialise()?).await?;
+        }
+        Ok(stream)
+    }
+
+    async fn alert_status(
+        &self,
+        alert_type: AlertType,
+    ) -> Result<AlertPayload> {
+        let msg = NotificationMessage::HubAlerts(HubAlert::request_update(
+            alert_type,
+        ));
+        self.request(msg, |reply| match reply {
+            NotificationMessage::HubAlerts(HubAlert {
+                alert_type: t,
+                operation: AlertOperation::Update,
+                payload: Some(payload),
+            }) if t == alert_type => Some(payload),
+            _ => None,
         })
+        .await
+    }
+
+    async fn hub_property(&self, property: HubProperty) -> Result<()> {
+        let msg = NotificationMessage::HubProperties(property);
+        self.send_raw(&msg.try_serialise()?).await
+    }
+
+    async fn request_property(
+        &self,
+        reference: HubPropertyReference,
+    ) -> Result<HubPropertyValue> {
+        let msg = NotificationMessage::HubProperties(
+            HubProperty::request_update(reference),
+        );
+        let value = self
+            .request(msg, |reply| match reply {
+                NotificationMessage::HubProperties(HubProperty {
+                    reference: r,
+                    operation: HubPropertyOperation::UpdateUpstream,
+                    property: Some(value),
+                }) if r == reference => Some(value),
+                _ => None,
+            })
+            .await?;
+        self.properties.lock().unwrap().update(&value);
+        Ok(value)
     }
 }
 
@@ -222,6 +938,7 @@ impl TechnicHub {
     pub async fn init(
         peripheral: Peripheral,
         lpf_characteristic: Characteristic,
+        hub_type: HubType,
     ) -> Result<Self> {
         // Peripheral is already connected before we get here
 
@@ -230,40 +947,471 @@ impl TechnicHub {
             .await?
             .context("No properties found for hub")?;
 
-        let mut port_map = PortMap::with_capacity(10);
-        port_map.insert(Port::A, 0);
-        port_map.insert(Port::B, 1);
-        port_map.insert(Port::C, 2);
-        port_map.insert(Port::D, 3);
-        port_map.insert(Port::HubLed, 50);
-        port_map.insert(Port::CurrentSensor, 59);
-        port_map.insert(Port::VoltageSensor, 60);
-        port_map.insert(Port::Accelerometer, 97);
-        port_map.insert(Port::GyroSensor, 98);
-        port_map.insert(Port::TiltSensor, 99);
-
         let properties = HubProperties {
             mac_address: props.address.to_string(),
             name: props.local_name.unwrap_or_default(),
-            rssi: props.tx_power_level.unwrap_or_default(),
-            port_map,
+            rssi: props.rssi.unwrap_or_default(),
             ..Default::default()
         };
+        let transport = BleTransport::new(peripheral, lpf_characteristic);
+        Self::with_transport(Arc::new(transport), hub_type, properties).await
+    }
+
+    /// Set up a hub that is already connected through `transport`, taking
+    /// what is known about it before connecting from `properties`. Its
+    /// ports start out as those of `hub_type`.
+    pub async fn with_transport(
+        transport: Arc<dyn Transport>,
+        hub_type: HubType,
+        properties: HubProperties,
+    ) -> Result<Self> {
+        let properties = HubProperties {
+            port_map: PortMap::for_hub_type(hub_type),
+            ..properties
+        };
+        let dispatcher = Dispatcher::spawn(transport.notifications().await?);
+
+        // The hub reports what is attached as soon as notifications are
+        // enabled, so start tracking before subscribing
+        let connected_io = Arc::new(Mutex::new(HashMap::new()));
+        let mut notifications = dispatcher.subscribe();
+        let properties = Arc::new(Mutex::new(properties));
+        let (events, _) = broadcast::channel(IO_EVENT_CAPACITY);
+        let io_events = Arc::new(Mutex::new(Some(events.clone())));
+        let io = connected_io.clone();
+        let props = properties.clone();
+        let subscriptions = io_events.clone();
+        tokio::spawn(async move {
+            while let Some(notification) = notifications.recv().await {
+                match notification.message {
+                    // Events go out after they are applied, so anyone who
+                    // subscribes before looking at the attached devices
+                    // sees every device one way or the other
+                    Ok(NotificationMessage::HubAttachedIo(event)) => {
+                        let event = {
+                            let port_map = &mut props.lock().unwrap().port_map;
+                            let event = IoEvent::new(&event, port_map);
+                            port_map.learn(&event);
+                            event
+                        };
+                        event.apply(&mut io.lock().unwrap());
+                        // Nobody listening is fine
+                        let _ = events.send(event);
+                    }
+                    // Only values the hub reports are cached, so a set it
+                    // rejects doesn't leave a wrong value behind
+                    Ok(NotificationMessage::HubProperties(HubProperty {
+                        operation: HubPropertyOperation::UpdateUpstream,
+                        property: Some(value),
+                        ..
+                    })) => props.lock().unwrap().update(&value),
+                    _ => {}
+                }
+            }
+            subscriptions.lock().unwrap().take();
+        });
+        transport.subscribe().await?;
 
         Ok(Self {
-            peripheral,
-            lpf_characteristic,
+            transport,
             properties,
-            connected_io: Default::default(),
+            connected_io,
+            io_events,
+            dispatcher,
         })
     }
 
-    // async fn port_from_id(&self, _port_id: u8) -> Option<Port> {
-    // for (k, v) in self.port_map().await.iter() {
-    //     if *v == port_id {
-    //         return Some(*k);
-    //     }
-    // }
-    // None
-    // }
+    /// Id of a port on this hub
+    fn port_id(&self, port: Port) -> Result<u8> {
+        self.properties
+            .lock()
+            .unwrap()
+            .port_map
+            .id(port)
+            .ok_or_else(|| {
+                crate::Error::NoneError(format!(
+                    "Port type `{port:?}` not supported"
+                ))
+            })
+    }
+
+    /// Stream of the notifications that `select` picks out and converts
+    async fn notification_stream<T, F>(
+        &self,
+        mut select: F,
+    ) -> Result<NotificationStream<T>>
+    where
+        T: Send + 'static,
+        F: FnMut(NotificationMessage) -> Option<T> + Send + 'static,
+    {
+        let notifications = self.notifications().into_stream();
+        Ok(Box::pin(notifications.filter_map(move |notification| {
+            futures::future::ready(
+                notification.message.ok().and_then(&mut select),
+            )
+        })))
+    }
+
+    /// Send a message and wait for the first notification that `reply`
+    /// picks out as the answer to it. Fails with `Error::Protocol` if the
+    /// hub rejects the message instead, and with `Error::TimeoutError` if
+    /// the hub doesn't answer within `REQUEST_TIMEOUT`.
+    async fn request<T, F>(
+        &self,
+        msg: NotificationMessage,
+        mut reply: F,
+    ) -> Result<T>
+    where
+        F: FnMut(NotificationMessage) -> Option<T> + Send,
+    {
+        let command = msg.message_type();
+        let mut notifications = self.notifications();
+        self.send_raw(&msg.try_serialise()?).await?;
+        let answer = tokio::time::timeout(REQUEST_TIMEOUT, async {
+            while let Some(notification) = notifications.recv().await {
+                match notification.message {
+                    Ok(NotificationMessage::GenericErrorMessages(error))
+                        if error.command_type as u8 == command
+                            && error.is_error() =>
+                    {
+                        return Err(Error::Protocol {
+                            command: error.command_type,
+                            code: error.error_code,
+                        });
+                    }
+                    Ok(msg) => {
+                        if let Some(answer) = reply(msg) {
+                            return Ok(answer);
+                        }
+                    }
+                    Err(_) => {}
+                }
+            }
+            Err(Error::HubError(
+                "Notifications ended before the hub replied".to_string(),
+            ))
+        })
+        .await;
+        answer.unwrap_or_else(|_| {
+            Err(Error::TimeoutError(format!(
+                "No reply from the hub to {:?}",
+                msg
+            )))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sim::SimHub;
+
+    async fn connect(sim: &SimHub) -> TechnicHub {
+        TechnicHub::with_transport(
+            Arc::new(sim.connect()),
+            sim.hub_type(),
+            HubProperties::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn io_events() {
+        let mut port_map = PortMap::new();
+        port_map.insert(Port::A, 0);
+        port_map.insert(Port::B, 1);
+        let mut connected = HashMap::new();
+
+        let attach = |bytes: &[u8]| {
+            let NotificationMessage::HubAttachedIo(event) =
+                NotificationMessage::parse(bytes).unwrap()
+            else {
+                panic!("wrong type");
+            };
+            IoEvent::new(&event, &port_map)
+        };
+
+        // Large linear motors attached to A and B
+        for port in [0, 1] {
+            let event = attach(&[
+                0x0f, 0x00, 0x04, port, 0x01, 0x2e, 0x00, 0x00, 0x00, 0x00,
+                0x10, 0x00, 0x00, 0x00, 0x10,
+            ]);
+            event.apply(&mut connected);
+        }
+        let io = &connected[&1];
+        assert_eq!(io.port, Port::B);
+        assert_eq!(io.io_type_id, IoTypeId::TechnicLargeLinearMotor);
+        assert_eq!(io.device_type(), DeviceType::TechnicLargeLinearMotor);
+        assert_eq!(io.hw_rev.unwrap().major, 1);
+
+        // Virtual port made of A and B
+        let event =
+            attach(&[0x09, 0x00, 0x04, 0x10, 0x02, 0x2e, 0x00, 0x00, 0x01]);
+        event.apply(&mut connected);
+        let io = &connected[&0x10];
+        assert_eq!(io.port, Port::Virtual(0x10));
+        assert_eq!(io.virtual_ports, Some((0, 1)));
+        assert_eq!(io.fw_rev, None);
+
+        // Detaching B
+        let event = attach(&[0x05, 0x00, 0x04, 0x01, 0x00]);
+        assert_eq!(
+            event,
+            IoEvent::Detached {
+                port: Port::B,
+                port_id: 1
+            }
+        );
+        event.apply(&mut connected);
+        assert!(!connected.contains_key(&1));
+        assert_eq!(connected.len(), 2);
+    }
+
+    #[test]
+    fn port_map() {
+        use crate::consts::HubType;
+
+        let technic = PortMap::for_hub_type(HubType::TechnicMediumHub);
+        for (port, id) in technic.ports() {
+            assert_eq!(technic.port(id), port);
+            if let Some(fixed) = port.id() {
+                assert_eq!(fixed, id);
+            }
+        }
+        assert_eq!(technic.id(Port::TiltSensor), Some(0x63));
+        assert_eq!(Port::TiltSensor.id(), None);
+        assert_eq!(Port::C.id(), Some(0x02));
+
+        // Hub specific ids
+        let move_hub = PortMap::for_hub_type(HubType::MoveHub);
+        assert_eq!(move_hub.id(Port::TiltSensor), Some(0x3a));
+        assert_eq!(move_hub.id(Port::GyroSensor), None);
+
+        // Unnamed internal port and a virtual port revealed by attach events
+        let mut port_map = technic.clone();
+        assert_eq!(port_map.port(0x3d), Port::Other(0x3d));
+        for bytes in [
+            &[
+                0x0f, 0x00, 0x04, 0x3d, 0x01, 0x3c, 0x00, 0x00, 0x00, 0x00,
+                0x10, 0x00, 0x00, 0x00, 0x10,
+            ][..],
+            &[0x09, 0x00, 0x04, 0x10, 0x02, 0x2e, 0x00, 0x00, 0x01],
+        ] {
+            let NotificationMessage::HubAttachedIo(event) =
+                NotificationMessage::parse(bytes).unwrap()
+            else {
+                panic!("wrong type");
+            };
+            port_map.learn(&IoEvent::new(&event, &port_map));
+        }
+        assert_eq!(port_map.id(Port::Other(0x3d)), Some(0x3d));
+        assert_eq!(port_map.port(0x10), Port::Virtual(0x10));
+        assert_eq!(port_map.ports().count(), technic.ports().count() + 2);
+
+        let detach =
+            NotificationMessage::parse(&[0x05, 0x00, 0x04, 0x10, 0x00])
+                .unwrap();
+        let NotificationMessage::HubAttachedIo(event) = detach else {
+            panic!("wrong type");
+        };
+        port_map.learn(&IoEvent::new(&event, &port_map));
+        assert_eq!(port_map.port(0x10), Port::Other(0x10));
+    }
+
+    #[tokio::test]
+    async fn io_events_end_with_connection() {
+        let sim = SimHub::technic_medium_hub("Sim")
+            .with_device(Port::A, IoTypeId::TechnicLargeLinearMotor);
+        let hub = connect(&sim).await;
+        let mut events = hub.io_events().await.unwrap();
+        let io = hub
+            .wait_for_device(Port::A, DeviceType::TechnicLargeLinearMotor)
+            .await
+            .unwrap();
+        assert_eq!(io.port_id, 0);
+
+        hub.disconnect().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while events.next().await.is_some() {}
+        })
+        .await
+        .unwrap();
+        assert!(hub.io_events().await.unwrap().next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn port_map_for_hub_type() {
+        let sim = SimHub::technic_medium_hub("Sim");
+        let hub = TechnicHub::with_transport(
+            Arc::new(sim.connect()),
+            HubType::MoveHub,
+            HubProperties::default(),
+        )
+        .await
+        .unwrap();
+        let port_map = hub.properties().await.port_map;
+        assert_eq!(port_map.id(Port::TiltSensor), Some(0x3a));
+        assert_eq!(port_map.port(0x63), Port::Other(0x63));
+    }
+
+    #[tokio::test]
+    async fn properties_cached_from_hub() {
+        let hub = connect(&SimHub::technic_medium_hub("Sim")).await;
+        assert_eq!(hub.battery_voltage().await.unwrap(), 100);
+        assert_eq!(hub.properties().await.battery_level, 100);
+
+        // The battery level can't be set, so the hub rejects it and the
+        // cached value stays
+        let mut errors = hub
+            .notifications()
+            .message_type(MessageType::GenericErrorMessages);
+        hub.hub_property(HubProperty::set(HubPropertyValue::BatteryVoltage(5)))
+            .await
+            .unwrap();
+        errors.recv().await.unwrap();
+        assert_eq!(hub.properties().await.battery_level, 100);
+
+        // Updates the hub sends by itself are cached too
+        let mut updates =
+            hub.notifications().message_type(MessageType::HubProperties);
+        hub.hub_property(HubProperty::enable_updates(
+            HubPropertyReference::Rssi,
+        ))
+        .await
+        .unwrap();
+        updates.recv().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while hub.properties().await.rssi != -50 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn port_values_decoded() {
+        use crate::notifications::{DatasetType, InputSetupSingle, Power};
+
+        let sim = SimHub::technic_medium_hub("Sim")
+            .with_device(Port::B, IoTypeId::TechnicLargeAngularMotor);
+        let hub = connect(&sim).await;
+        let mut motor = hub.port(Port::B).await.unwrap();
+
+        // Speed is reported in mode 1 as a single i8
+        let format = hub.value_format(Port::B, 1).await.unwrap();
+        assert_eq!(format.number_of_datasets, 1);
+        assert_eq!(format.dataset_type, DatasetType::Bits8);
+        assert_eq!(
+            hub.dispatcher.value_formats().value_format(1, 1),
+            Some(format)
+        );
+        let err = hub.value_format(Port::B, 0).await.unwrap_err();
+        assert!(matches!(err, Error::Protocol { .. }), "{:?}", err);
+
+        let mut values = hub.port_values(Port::B).await.unwrap();
+        let setup =
+            NotificationMessage::PortInputFormatSetupSingle(InputSetupSingle {
+                port_id: 1,
+                mode: 1,
+                delta: 1,
+                notification_enabled: true,
+            });
+        hub.send_raw(&setup.serialise()).await.unwrap();
+        assert_eq!(values.next().await, Some(vec![TypedValue::Bits8(0)]));
+
+        motor.start_speed(50, Power::Cw(100)).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while values.next().await != Some(vec![TypedValue::Bits8(50)]) {}
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn combined_values_decoded() {
+        use crate::notifications::{
+            InputSetupCombined, InputSetupCombinedSubcommand, InputSetupSingle,
+            Power,
+        };
+
+        let sim = SimHub::technic_medium_hub("Sim")
+            .with_device(Port::B, IoTypeId::TechnicLargeAngularMotor);
+        let hub = connect(&sim).await;
+        let mut motor = hub.port(Port::B).await.unwrap();
+        hub.value_format(Port::B, 1).await.unwrap();
+        hub.value_format(Port::B, 2).await.unwrap();
+
+        // Speed and position together
+        let mut values = hub.combined_values(Port::B).await.unwrap();
+        let combined = |subcommand| {
+            NotificationMessage::PortInputFormatSetupCombinedmode(
+                InputSetupCombined {
+                    port_id: 1,
+                    subcommand,
+                },
+            )
+        };
+        let single = |mode| {
+            NotificationMessage::PortInputFormatSetupSingle(InputSetupSingle {
+                port_id: 1,
+                mode,
+                delta: 1,
+                notification_enabled: true,
+            })
+        };
+        for msg in [
+            combined(InputSetupCombinedSubcommand::LockLpf2DeviceForSetup),
+            single(1),
+            single(2),
+            combined(
+                InputSetupCombinedSubcommand::SetModeanddatasetCombinations {
+                    combination_index: 0,
+                    mode_dataset: vec![0x10, 0x20],
+                },
+            ),
+            combined(InputSetupCombinedSubcommand::UnlockAndStartMultiEnabled),
+        ] {
+            hub.send_raw(&msg.serialise()).await.unwrap();
+        }
+        let value = |mode, value| CombinedValue {
+            mode,
+            dataset: 0,
+            value,
+        };
+        assert_eq!(
+            values.next().await,
+            Some(vec![
+                value(1, TypedValue::Bits8(0)),
+                value(2, TypedValue::Bits32(0)),
+            ])
+        );
+
+        motor.start_speed(100, Power::Cw(100)).await.unwrap();
+        let moved = tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                let values = values.next().await.unwrap();
+                if values[1].value != TypedValue::Bits32(0) {
+                    return values;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(moved[0].mode, 1);
+        assert_ne!(moved[0].value, TypedValue::Bits8(0));
+        assert_eq!(moved[1].mode, 2);
+    }
+
+    #[tokio::test]
+    async fn request_timeout() {
+        let hub = connect(&SimHub::technic_medium_hub("Sim")).await;
+        // The hub doesn't answer busy indication
+        let msg =
+            NotificationMessage::HubActions(HubAction::ActivateBusyIndication);
+        let err = hub.request(msg, |_| None::<()>).await.unwrap_err();
+        assert!(matches!(err, Error::TimeoutError(_)), "{:?}", err);
+    }
 }
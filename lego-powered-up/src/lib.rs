@@ -5,24 +5,36 @@ use btleplug::api::{
 use btleplug::platform::{Adapter, Manager, PeripheralId};
 use futures::{stream::StreamExt, Stream};
 use num_traits::FromPrimitive;
+use std::fmt::{self, Display};
+use std::sync::Arc;
 
 #[macro_use]
 extern crate log;
 
+#[macro_use]
+mod macros;
+
+pub mod btsnoop;
+pub mod capture;
 pub mod consts;
 pub mod devices;
+pub mod dispatcher;
 pub mod error;
 pub mod hubs;
 pub mod notifications;
+pub mod sim;
+pub mod transport;
 
 pub use btleplug;
 pub use error::{Error, OptionContext, Result};
 
 use consts::{BLEManufacturerData, HubType};
 use hubs::Hub;
+use sim::SimHub;
 
 pub struct PoweredUp {
-    adapter: Adapter,
+    adapter: Option<Adapter>,
+    simulated: Vec<SimHub>,
 }
 
 impl PoweredUp {
@@ -54,11 +66,41 @@ impl PoweredUp {
     }
 
     pub async fn with_adapter(adapter: Adapter) -> Result<Self> {
-        Ok(Self { adapter })
+        Ok(Self {
+            adapter: Some(adapter),
+            simulated: Vec::new(),
+        })
+    }
+
+    /// Without a Bluetooth adapter, so only simulated hubs can be found
+    pub fn without_adapter() -> Self {
+        Self {
+            adapter: None,
+            simulated: Vec::new(),
+        }
+    }
+
+    /// Make a simulated hub discoverable alongside the real ones
+    pub fn add_simulated_hub(&mut self, hub: SimHub) {
+        self.simulated.push(hub);
+    }
+
+    fn adapter(&self) -> Result<&Adapter> {
+        self.adapter.as_ref().context("No adapter found")
+    }
+
+    fn simulated_hubs(&self) -> impl Iterator<Item = DiscoveredHub> + '_ {
+        self.simulated.iter().map(|sim| DiscoveredHub {
+            hub_type: sim.hub_type(),
+            addr: HubAddress::Simulated(sim.address()),
+            name: sim.name().to_string(),
+        })
     }
 
     pub async fn run(&mut self) -> Result<()> {
-        self.adapter.start_scan(ScanFilter::default()).await?;
+        if let Some(adapter) = &self.adapter {
+            adapter.start_scan(ScanFilter::default()).await?;
+        }
         Ok(())
     }
 
@@ -68,14 +110,17 @@ impl PoweredUp {
     }
 
     pub async fn list_discovered_hubs(&mut self) -> Result<Vec<DiscoveredHub>> {
-        let peripherals = self.adapter.peripherals().await?;
-        let mut hubs = Vec::new();
+        let mut hubs: Vec<_> = self.simulated_hubs().collect();
+        let Some(adapter) = &self.adapter else {
+            return Ok(hubs);
+        };
+        let peripherals = adapter.peripherals().await?;
         for peripheral in peripherals {
             let Some(props) = peripheral.properties().await? else{continue;};
             if let Some(hub_type) = identify_hub(&props).await? {
                 hubs.push(DiscoveredHub {
                     hub_type,
-                    addr: peripheral.id(),
+                    addr: HubAddress::Ble(peripheral.id()),
                     name: props
                         .local_name
                         .unwrap_or_else(|| "unknown".to_string()),
@@ -88,25 +133,30 @@ impl PoweredUp {
     pub async fn scan(
         &mut self,
     ) -> Result<impl Stream<Item = DiscoveredHub> + '_> {
-        let events = self.adapter.events().await?;
-        self.adapter.start_scan(ScanFilter::default()).await?;
-        Ok(events.filter_map(|event| async {
+        let simulated = futures::stream::iter(self.simulated_hubs());
+        let Some(adapter) = &self.adapter else {
+            return Ok(simulated.chain(futures::stream::empty().left_stream()));
+        };
+        let events = adapter.events().await?;
+        adapter.start_scan(ScanFilter::default()).await?;
+        let discovered = events.filter_map(move |event| async move {
             let CentralEvent::DeviceDiscovered(id) = event else { None? };
             // get peripheral info
-            let peripheral = self.adapter.peripheral(&id).await.ok()?;
+            let peripheral = adapter.peripheral(&id).await.ok()?;
             // println!("{:?}", peripheral.properties().await?);
             let Some(props) = peripheral.properties().await.ok()? else { None? };
             if let Some(hub_type) = identify_hub(&props).await.ok()? {
                 let hub = DiscoveredHub {
                     hub_type,
-                    addr: id,
+                    addr: HubAddress::Ble(id),
                     name: props
                         .local_name
                         .unwrap_or_else(|| "unknown".to_string()),
                 };
                 Some(hub)
             } else { None }
-        }))
+        });
+        Ok(simulated.chain(discovered.right_stream()))
     }
 
     pub async fn wait_for_hub(&mut self) -> Result<DiscoveredHub> {
@@ -117,24 +167,29 @@ impl PoweredUp {
         &mut self,
         filter: HubFilter,
     ) -> Result<DiscoveredHub> {
-        let mut events = self.adapter.events().await?;
-        self.adapter.start_scan(ScanFilter::default()).await?;
+        if let Some(hub) = self.simulated_hubs().find(|hub| filter.matches(hub))
+        {
+            return Ok(hub);
+        }
+        let adapter = self.adapter()?;
+        let mut events = adapter.events().await?;
+        adapter.start_scan(ScanFilter::default()).await?;
         while let Some(event) = events.next().await {
             let CentralEvent::DeviceDiscovered(id) = event else { continue };
             // get peripheral info
-            let peripheral = self.adapter.peripheral(&id).await?;
+            let peripheral = adapter.peripheral(&id).await?;
             // println!("{:?}", peripheral.properties().await?);
             let Some(props) = peripheral.properties().await? else { continue };
             if let Some(hub_type) = identify_hub(&props).await? {
                 let hub = DiscoveredHub {
                     hub_type,
-                    addr: id,
+                    addr: HubAddress::Ble(id),
                     name: props
                         .local_name
                         .unwrap_or_else(|| "unknown".to_string()),
                 };
                 if filter.matches(&hub) {
-                    self.adapter.stop_scan().await?;
+                    adapter.stop_scan().await?;
                     return Ok(hub);
                 }
             }
@@ -148,27 +203,52 @@ impl PoweredUp {
     ) -> Result<Box<dyn Hub>> {
         info!("Connecting to hub {}...", hub.addr,);
 
-        let peripheral = self.adapter.peripheral(&hub.addr).await?;
+        let id = match &hub.addr {
+            HubAddress::Ble(id) => id,
+            HubAddress::Simulated(address) => {
+                let sim = self
+                    .simulated
+                    .iter()
+                    .find(|sim| sim.address() == *address)
+                    .context("No such simulated hub")?;
+                let properties = hubs::HubProperties {
+                    name: sim.name().to_string(),
+                    mac_address: sim.address(),
+                    ..Default::default()
+                };
+                let transport = Arc::new(sim.connect());
+                return Ok(Box::new(
+                    hubs::TechnicHub::with_transport(
+                        transport,
+                        sim.hub_type(),
+                        properties,
+                    )
+                    .await?,
+                ));
+            }
+        };
+        // Every other hub type speaks LWP3 over the LPF2 characteristic
+        if hub.hub_type == HubType::Wedo2SmartHub {
+            return Err(Error::NotImplementedError(format!(
+                "Connecting to a {}",
+                hub.hub_type
+            )));
+        }
+        let peripheral = self.adapter()?.peripheral(id).await?;
         peripheral.connect().await?;
         peripheral.discover_services().await?;
         // tokio::time::sleep(Duration::from_secs(2)).await;
         let chars = peripheral.characteristics();
 
-        dbg!(&chars);
-
         let lpf_char = chars
             .iter()
             .find(|c| c.uuid == *consts::blecharacteristic::LPF2_ALL)
             .context("Device does not advertise LPF2_ALL characteristic")?
             .clone();
-        peripheral.subscribe(&lpf_char).await?;
 
-        Ok(Box::new(match hub.hub_type {
-            HubType::TechnicMediumHub => {
-                hubs::TechnicHub::init(peripheral, lpf_char).await?
-            }
-            _ => unimplemented!(),
-        }))
+        Ok(Box::new(
+            hubs::TechnicHub::init(peripheral, lpf_char, hub.hub_type).await?,
+        ))
     }
 }
 
@@ -189,7 +269,10 @@ impl HubFilter {
         use HubFilter::*;
         match self {
             Name(n) => hub.name == *n,
-            Addr(a) => format!("{:?}", hub.addr) == *a,
+            Addr(a) => match &hub.addr {
+                HubAddress::Ble(id) => format!("{:?}", id) == *a,
+                HubAddress::Simulated(address) => address == a,
+            },
             Null => true,
         }
     }
@@ -202,11 +285,31 @@ pub struct DiscoveredHub {
     /// Type of hub, e.g. TechnicMediumHub
     pub hub_type: HubType,
     /// BLE address
-    pub addr: PeripheralId,
+    pub addr: HubAddress,
     /// Friendly name of the hub, as set in the PoweredUp/Control+ apps
     pub name: String,
 }
 
+/// Where a discovered hub can be reached
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HubAddress {
+    Ble(PeripheralId),
+    /// Address of a simulated hub added with
+    /// `PoweredUp::add_simulated_hub`
+    Simulated(String),
+}
+
+impl Display for HubAddress {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HubAddress::Ble(id) => write!(fmt, "{}", id),
+            HubAddress::Simulated(address) => {
+                write!(fmt, "{} (simulated)", address)
+            }
+        }
+    }
+}
+
 async fn identify_hub(props: &PeripheralProperties) -> Result<Option<HubType>> {
     use HubType::*;
 
@@ -6,78 +6,13 @@
 
 use crate::consts::*;
 use crate::error::{Error, OptionContext, Result};
-use log::{debug, trace};
-use lpu_macros::Parse;
+use log::{debug, trace, warn};
+use lpu_macros::{Parse, Serialise};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 use std::collections::HashMap;
 use std::fmt::{self, Debug, Display};
 
-macro_rules! ok {
-    ($thing:expr) => {
-        $thing.context("Cannot convert 'None'")?
-    };
-}
-
-macro_rules! next {
-    ($iter:ident) => {
-        *$iter.next().context("Insufficient length")?
-    };
-}
-
-macro_rules! four_bytes {
-    ($t:ty, $iter:ident) => {
-        <$t>::from_le_bytes([
-            next!($iter),
-            next!($iter),
-            next!($iter),
-            next!($iter),
-        ])
-    };
-}
-
-macro_rules! two_bytes {
-    ($t:ty, $iter:ident) => {
-        <$t>::from_le_bytes([next!($iter), next!($iter)])
-    };
-}
-
-macro_rules! next_i32 {
-    ($iter:ident) => {
-        four_bytes!(i32, $iter)
-    };
-}
-
-macro_rules! next_u32 {
-    ($iter:ident) => {
-        four_bytes!(u32, $iter)
-    };
-}
-
-macro_rules! next_f32 {
-    ($iter:ident) => {
-        four_bytes!(f32, $iter)
-    };
-}
-
-macro_rules! next_u16 {
-    ($iter:ident) => {
-        two_bytes!(u16, $iter)
-    };
-}
-
-macro_rules! next_i16 {
-    ($iter:ident) => {
-        two_bytes!(i16, $iter)
-    };
-}
-
-macro_rules! next_i8 {
-    ($iter:ident) => {
-        i8::from_le_bytes([next!($iter)])
-    };
-}
-
 pub const MAX_NAME_SIZE: usize = 14;
 
 /// Message format:
@@ -105,7 +40,7 @@ pub const MAX_NAME_SIZE: usize = 14;
 pub enum NotificationMessage {
     HubProperties(HubProperty),
     HubActions(HubAction),
-    HubAlerts(AlertType),
+    HubAlerts(HubAlert),
     HubAttachedIo(AttachedIo),
     GenericErrorMessages(ErrorMessageFormat),
     HwNetworkCommands(NetworkCommand),
@@ -162,7 +97,7 @@ impl NotificationMessage {
                 HubActions(action)
             }
             MessageType::HubAlerts => {
-                let alert = AlertType::parse(&mut msg_iter)?;
+                let alert = HubAlert::parse(&mut msg_iter)?;
                 HubAlerts(alert)
             }
             MessageType::HubAttachedIo => {
@@ -293,6 +228,49 @@ impl NotificationMessage {
         }) as u8
     }
 
+    /// The port a message is about, if any. Port value and feedback
+    /// messages may cover several ports, in which case this is the first;
+    /// see `port_ids`.
+    pub fn port_id(&self) -> Option<u8> {
+        use NotificationMessage::*;
+        match self {
+            HubAttachedIo(io) => Some(io.port),
+            PortInformationRequest(req) => Some(req.port_id),
+            PortModeInformationRequest(req) => Some(req.port_id),
+            PortInputFormatSetupSingle(setup) => Some(setup.port_id),
+            PortInputFormatSetupCombinedmode(setup) => Some(setup.port_id),
+            PortInformation(info) => Some(info.port_id),
+            PortModeInformation(info) => Some(info.port_id),
+            PortValueSingle(value) => value.payload.first().copied(),
+            PortValueCombinedmode(value) => Some(value.port_id),
+            PortInputFormatSingle(fmt) => Some(fmt.port_id),
+            PortInputFormatCombinedmode(fmt) => Some(fmt.port_id),
+            VirtualPortSetup(VirtualPortSetupFormat::Disconnect {
+                port_id,
+            }) => Some(*port_id),
+            PortOutputCommand(cmd) => Some(cmd.port_id),
+            PortOutputCommandFeedback(feedback) => Some(feedback.msg1.port_id),
+            _ => None,
+        }
+    }
+
+    /// Every port a message is about. Feedback messages list each of their
+    /// ports. Port value messages can cover several ports too, but the
+    /// ports after the first can only be found with their value formats,
+    /// which `HubNotification::port_ids` uses.
+    pub fn port_ids(&self) -> Vec<u8> {
+        match self {
+            NotificationMessage::PortOutputCommandFeedback(feedback) => {
+                [Some(feedback.msg1), feedback.msg2, feedback.msg3]
+                    .iter()
+                    .flatten()
+                    .map(|msg| msg.port_id)
+                    .collect()
+            }
+            msg => msg.port_id().into_iter().collect(),
+        }
+    }
+
     fn validate_length<'a>(
         mut msg: impl Iterator<Item = &'a u8>,
         supplied: usize,
@@ -317,7 +295,7 @@ impl NotificationMessage {
         } else {
             // high bit set - length is both bytes with a bit missing
             let second = next!(msg); // only advance if needed
-            eprintln!("second: {:x}", second);
+            trace!("Extended length byte: {:x}", second);
             ((second as usize) << 7) | ((first & 0x7f) as usize)
         };
 
@@ -329,47 +307,151 @@ impl NotificationMessage {
         buf.iter().fold(0xff, |acc, x| acc ^ x)
     }
 
+    /// Longest frame the length header can describe
+    pub const MAX_LENGTH: usize = 0x7fff;
+
     /// Serialise a notification message into a Vec<u8>
-    /// TODO no alloc
+    ///
+    /// Panics if the message is longer than `MAX_LENGTH`; use
+    /// `try_serialise` for messages built from outside data.
     pub fn serialise(&self) -> Vec<u8> {
+        self.try_serialise()
+            .expect("Message is too long to serialise")
+    }
+
+    /// Serialise a notification message into a Vec<u8>, or fail if it is
+    /// longer than `MAX_LENGTH`, as its length can't be encoded.
+    /// TODO no alloc
+    pub fn try_serialise(&self) -> Result<Vec<u8>> {
         use NotificationMessage::*;
 
         let mut ser = match self {
-            HubProperties(_) => todo!(),
-            HubActions(_) => todo!(),
-            HubAlerts(_) => todo!(),
-            HubAttachedIo(_) => todo!(),
-            GenericErrorMessages(_) => todo!(),
-            HwNetworkCommands(_) => todo!(),
-            FwUpdateGoIntoBootMode(_) => todo!(),
-            FwUpdateLockMemory(_) => todo!(),
-            FwUpdateLockStatusRequest => todo!(),
-            FwLockStatus(_) => todo!(),
-            PortInformationRequest(_) => todo!(),
-            PortModeInformationRequest(_) => {
-                todo!()
+            HubProperties(prop) => prop.serialise(),
+            HubActions(action) => {
+                vec![0, 0, self.message_type(), *action as u8]
             }
-            PortInputFormatSetupSingle(msg) => msg.serialise(),
-            PortInputFormatSetupCombinedmode(_) => {
-                todo!()
+            HubAlerts(alert) => alert.serialise(),
+            HubAttachedIo(attach) => attach.serialise(),
+            GenericErrorMessages(error) => error.serialise(),
+            HwNetworkCommands(command) => command.serialise(),
+            FwUpdateGoIntoBootMode(safety) => {
+                let mut ser = vec![0, 0, self.message_type()];
+                ser.extend_from_slice(safety);
+                ser
             }
-            PortInformation(_) => todo!(),
-            PortModeInformation(_) => todo!(),
-            PortValueSingle(_) => todo!(),
-            PortValueCombinedmode(_) => todo!(),
-            PortInputFormatSingle(_) => todo!(),
-            PortInputFormatCombinedmode(_) => {
-                todo!()
+            FwUpdateLockMemory(safety) => {
+                let mut ser = vec![0, 0, self.message_type()];
+                ser.extend_from_slice(safety);
+                ser
             }
-            VirtualPortSetup(_) => todo!(),
-            PortOutputCommand(cmd) => cmd.serialise(),
-            PortOutputCommandFeedback(_) => {
-                todo!()
+            FwUpdateLockStatusRequest => vec![0, 0, self.message_type()],
+            FwLockStatus(status) => {
+                vec![0, 0, self.message_type(), *status as u8]
             }
+            PortInformationRequest(req) => req.serialise(),
+            PortModeInformationRequest(req) => req.serialise(),
+            PortInputFormatSetupSingle(msg) => msg.serialise(),
+            PortInputFormatSetupCombinedmode(setup) => setup.serialise(),
+            PortInformation(info) => info.serialise(),
+            PortModeInformation(info) => info.serialise(),
+            PortValueSingle(value) => value.serialise(),
+            PortValueCombinedmode(value) => value.serialise(),
+            PortInputFormatSingle(fmt) => fmt.serialise(),
+            PortInputFormatCombinedmode(fmt) => fmt.serialise(),
+            VirtualPortSetup(setup) => setup.serialise(),
+            PortOutputCommand(cmd) => cmd.serialise(),
+            PortOutputCommandFeedback(feedback) => feedback.serialise(),
         };
-        ser[0] = ser.len() as u8;
+        Self::set_length(&mut ser)?;
         debug!("Serialised to: {:02x?}", ser);
-        ser
+        Ok(ser)
+    }
+
+    /// Fill in the length header of a serialised message. The length
+    /// counts the header itself, so messages that don't fit in 7 bits get a
+    /// second length byte and the rest of the frame is shifted along.
+    fn set_length(ser: &mut Vec<u8>) -> Result<()> {
+        let len = ser.len();
+        if len < 0x80 {
+            ser[0] = len as u8;
+        } else {
+            let len = len + 1;
+            if len > Self::MAX_LENGTH {
+                return Err(Error::ParseError(format!(
+                    "Message of {} bytes is too long to serialise",
+                    len
+                )));
+            }
+            ser[0] = (len & 0x7f) as u8 | 0x80;
+            ser.insert(1, (len >> 7) as u8);
+        }
+        Ok(())
+    }
+}
+
+/// Splits a byte stream into notification messages. BLE notifications
+/// carry exactly one frame each, but logs, sockets and serial bridges may
+/// split a frame across reads or deliver several in one, so bytes are
+/// buffered here until a whole frame, as given by its length header, is
+/// available.
+#[derive(Clone, Debug, Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+}
+
+impl FrameDecoder {
+    /// Smallest valid frame: length, hub id and message type
+    const MIN_FRAME: usize = 3;
+
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add bytes received from the stream
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Number of bytes held that aren't yet part of a complete frame
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Take the next complete frame, length header included. A length
+    /// header too short to be a frame can't be skipped over, so its first
+    /// byte is dropped to resynchronise on the following bytes.
+    pub fn next_frame(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let first = *self.buf.first()?;
+            let header_len = if first & 0x80 == 0 { 1 } else { 2 };
+            if self.buf.len() < header_len {
+                return None;
+            }
+            let len = NotificationMessage::length(self.buf.iter()).ok()?;
+            if len < header_len + Self::MIN_FRAME - 1 {
+                warn!("Invalid frame length {}, skipping a byte", len);
+                self.buf.remove(0);
+                continue;
+            }
+            if self.buf.len() < len {
+                return None;
+            }
+            return Some(self.buf.drain(..len).collect());
+        }
+    }
+
+    /// Take and parse the next complete frame. A frame that fails to parse
+    /// is still consumed, so decoding carries on with the next one.
+    pub fn next_message(&mut self) -> Option<Result<NotificationMessage>> {
+        self.next_frame()
+            .map(|frame| NotificationMessage::parse(&frame))
+    }
+
+    /// Add bytes received from the stream and parse every frame completed
+    /// by them
+    pub fn decode(&mut self, bytes: &[u8]) -> Vec<Result<NotificationMessage>> {
+        self.push(bytes);
+        std::iter::from_fn(|| self.next_message()).collect()
     }
 }
 
@@ -383,23 +465,97 @@ pub enum HubLedMode {
     Rgb = 0x01,
 }
 
+/// Only the `SetDownstream` and `UpdateUpstream` operations carry a
+/// property value; the remaining operations are just the property
+/// reference and the operation
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct HubProperty {
-    property: HubPropertyValue,
-    operation: HubPropertyOperation,
+    pub reference: HubPropertyReference,
+    pub operation: HubPropertyOperation,
+    pub property: Option<HubPropertyValue>,
 }
 
 impl HubProperty {
+    /// Set a property on the hub
+    pub fn set(value: HubPropertyValue) -> Self {
+        Self {
+            reference: value.reference(),
+            operation: HubPropertyOperation::SetDownstream,
+            property: Some(value),
+        }
+    }
+
+    /// Ask the hub to send an update whenever the property changes
+    pub fn enable_updates(reference: HubPropertyReference) -> Self {
+        Self::operation(
+            reference,
+            HubPropertyOperation::EnableUpdatesDownstream,
+        )
+    }
+
+    pub fn disable_updates(reference: HubPropertyReference) -> Self {
+        Self::operation(
+            reference,
+            HubPropertyOperation::DisableUpdatesDownstream,
+        )
+    }
+
+    /// Reset a property to its default, e.g. the advertising name
+    pub fn reset(reference: HubPropertyReference) -> Self {
+        Self::operation(reference, HubPropertyOperation::ResetDownstream)
+    }
+
+    /// Ask the hub to send the property's current value once
+    pub fn request_update(reference: HubPropertyReference) -> Self {
+        Self::operation(
+            reference,
+            HubPropertyOperation::RequestUpdateDownstream,
+        )
+    }
+
+    fn operation(
+        reference: HubPropertyReference,
+        operation: HubPropertyOperation,
+    ) -> Self {
+        Self {
+            reference,
+            operation,
+            property: None,
+        }
+    }
+
     pub fn parse<'a>(mut msg: impl Iterator<Item = &'a u8>) -> Result<Self> {
         let property_int = next!(msg);
+        let reference = ok!(HubPropertyReference::from_u8(property_int));
         let operation = ok!(HubPropertyOperation::from_u8(next!(msg)));
-        let property = HubPropertyValue::parse(property_int, &mut msg)?;
+        let property = match operation {
+            HubPropertyOperation::SetDownstream
+            | HubPropertyOperation::UpdateUpstream => {
+                Some(HubPropertyValue::parse(property_int, &mut msg)?)
+            }
+            _ => None,
+        };
 
         Ok(Self {
+            reference,
             operation,
             property,
         })
     }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        let mut msg = vec![
+            0,
+            0,
+            MessageType::HubProperties as u8,
+            self.reference as u8,
+            self.operation as u8,
+        ];
+        if let Some(property) = &self.property {
+            msg.extend_from_slice(&property.serialise());
+        }
+        msg
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -422,6 +578,26 @@ pub enum HubPropertyValue {
 }
 
 impl HubPropertyValue {
+    /// Advertising name to set on a hub. Names must be 1 to
+    /// `MAX_NAME_SIZE` printable ASCII characters.
+    pub fn advertising_name(name: &str) -> Result<Self> {
+        if name.is_empty() || name.len() > MAX_NAME_SIZE {
+            return Err(Error::HubError(format!(
+                "Hub name must be 1 to {} bytes long, `{}` is {}",
+                MAX_NAME_SIZE,
+                name,
+                name.len()
+            )));
+        }
+        if !name.bytes().all(|b| b.is_ascii_graphic() || b == b' ') {
+            return Err(Error::HubError(format!(
+                "Hub name must be printable ASCII: `{}`",
+                name
+            )));
+        }
+        Ok(Self::AdvertisingName(name.as_bytes().to_vec()))
+    }
+
     pub fn parse<'a>(
         prop_type: u8,
         mut msg: impl Iterator<Item = &'a u8>,
@@ -491,17 +667,70 @@ impl HubPropertyValue {
             }
         })
     }
+
+    /// The property reference under which this value is sent
+    pub fn reference(&self) -> HubPropertyReference {
+        use HubPropertyValue::*;
+        match self {
+            AdvertisingName(_) => HubPropertyReference::AdvertisingName,
+            Button(_) => HubPropertyReference::Button,
+            FwVersion(_) => HubPropertyReference::FwVersion,
+            HwVersion(_) => HubPropertyReference::HwVersion,
+            Rssi(_) => HubPropertyReference::Rssi,
+            BatteryVoltage(_) => HubPropertyReference::BatteryVoltage,
+            BatteryType(_) => HubPropertyReference::BatteryType,
+            ManufacturerName(_) => HubPropertyReference::ManufacturerName,
+            RadioFirmwareVersion(_) => {
+                HubPropertyReference::RadioFirmwareVersion
+            }
+            LegoWirelessProtocolVersion(_) => {
+                HubPropertyReference::LegoWirelessProtocolVersion
+            }
+            SystemTypeId(_) => HubPropertyReference::SystemTypeId,
+            HwNetworkId(_) => HubPropertyReference::HwNetworkId,
+            PrimaryMacAddress(_) => HubPropertyReference::PrimaryMacAddress,
+            SecondaryMacAddress => HubPropertyReference::SecondaryMacAddress,
+            HardwareNetworkFamily(_) => {
+                HubPropertyReference::HardwareNetworkFamily
+            }
+        }
+    }
+
+    /// Serialise the payload of the property, without the reference byte
+    pub fn serialise(&self) -> Vec<u8> {
+        use HubPropertyValue::*;
+        match self {
+            AdvertisingName(bytes)
+            | ManufacturerName(bytes)
+            | RadioFirmwareVersion(bytes) => bytes.clone(),
+            Button(val)
+            | BatteryVoltage(val)
+            | SystemTypeId(val)
+            | HwNetworkId(val)
+            | HardwareNetworkFamily(val) => vec![*val],
+            FwVersion(vers) | HwVersion(vers) => vers.to_le_bytes().to_vec(),
+            Rssi(rssi) => rssi.to_le_bytes().to_vec(),
+            BatteryType(typ) => vec![*typ as u8],
+            LegoWirelessProtocolVersion(vers) => vers.to_le_bytes().to_vec(),
+            PrimaryMacAddress(mac) => mac.to_vec(),
+            SecondaryMacAddress => Vec::new(),
+        }
+    }
 }
 
 #[repr(u8)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse, Serialise,
+)]
 pub enum HubBatteryType {
     Normal = 0x00,
     Rechargeable = 0x01,
 }
 
 #[repr(u8)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse, Serialise,
+)]
 pub enum HubAction {
     SwitchOffHub = 0x01,
     Disconnect = 0x02,
@@ -515,8 +744,23 @@ pub enum HubAction {
     HubWillGoIntoBootMode = 0x32,
 }
 
+impl HubAction {
+    /// Whether this is a warning from the hub that it is about to go away,
+    /// rather than an action requested of the hub
+    pub fn is_upstream(&self) -> bool {
+        matches!(
+            self,
+            HubAction::HubWillSwitchOff
+                | HubAction::HubWillDisconnect
+                | HubAction::HubWillGoIntoBootMode
+        )
+    }
+}
+
 #[repr(u8)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse, Serialise,
+)]
 pub enum AlertType {
     LowVoltage = 0x01,
     HighCurrent = 0x02,
@@ -524,31 +768,100 @@ pub enum AlertType {
     OverPowerCondition = 0x04,
 }
 
+#[repr(u8)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse, Serialise,
+)]
+pub enum AlertOperation {
+    EnableUpdates = 0x01,
+    DisableUpdates = 0x02,
+    RequestUpdates = 0x03,
+    Update = 0x04,
+}
+
+/// Only the upstream `Update` operation carries the alert status; the
+/// remaining operations are just the alert type and the operation
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
-pub struct AttachedIo {
-    pub port: u8,
-    pub event: IoAttachEvent,
+pub struct HubAlert {
+    pub alert_type: AlertType,
+    pub operation: AlertOperation,
+    pub payload: Option<AlertPayload>,
 }
 
-impl AttachedIo {
+impl HubAlert {
+    /// Ask the hub to send an update whenever the alert status changes
+    pub fn enable_updates(alert_type: AlertType) -> Self {
+        Self::operation(alert_type, AlertOperation::EnableUpdates)
+    }
+
+    pub fn disable_updates(alert_type: AlertType) -> Self {
+        Self::operation(alert_type, AlertOperation::DisableUpdates)
+    }
+
+    /// Ask the hub to send the current alert status once
+    pub fn request_update(alert_type: AlertType) -> Self {
+        Self::operation(alert_type, AlertOperation::RequestUpdates)
+    }
+
+    fn operation(alert_type: AlertType, operation: AlertOperation) -> Self {
+        Self {
+            alert_type,
+            operation,
+            payload: None,
+        }
+    }
+
     pub fn parse<'a>(mut msg: impl Iterator<Item = &'a u8>) -> Result<Self> {
-        let port = next!(msg);
-        let event = IoAttachEvent::parse(&mut msg)?;
-        Ok(Self { port, event })
+        let alert_type = AlertType::parse(&mut msg)?;
+        let operation = AlertOperation::parse(&mut msg)?;
+        let payload = match operation {
+            AlertOperation::Update => {
+                Some(ok!(AlertPayload::from_u8(next!(msg))))
+            }
+            _ => None,
+        };
+        Ok(Self {
+            alert_type,
+            operation,
+            payload,
+        })
+    }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        let mut msg = vec![
+            0,
+            0,
+            MessageType::HubAlerts as u8,
+            self.alert_type as u8,
+            self.operation as u8,
+        ];
+        if let Some(payload) = self.payload {
+            msg.push(payload as u8);
+        }
+        msg
     }
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Parse, Serialise)]
+#[lpu(message_type = "HubAttachedIo")]
+pub struct AttachedIo {
+    pub port: u8,
+    pub event: IoAttachEvent,
+}
+
+/// Both attach events carry the type of the attached device; a detach
+/// event is just the port
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum IoAttachEvent {
-    DetachedIo {
-        io_type_id: IoTypeId,
-    },
+    DetachedIo,
     AttachedIo {
+        io_type_id: IoTypeId,
         hw_rev: VersionNumber,
         fw_rev: VersionNumber,
     },
     AttachedVirtualIo {
+        io_type_id: IoTypeId,
         port_a: u8,
         port_b: u8,
     },
@@ -559,22 +872,58 @@ impl IoAttachEvent {
         let event_type = ok!(Event::from_u8(next!(msg)));
 
         Ok(match event_type {
-            Event::DetachedIo => {
-                let io_type_id = ok!(IoTypeId::from_u16(next_u16!(msg)));
-                IoAttachEvent::DetachedIo { io_type_id }
-            }
+            Event::DetachedIo => IoAttachEvent::DetachedIo,
             Event::AttachedIo => {
+                let io_type_id = ok!(IoTypeId::from_u16(next_u16!(msg)));
                 let hw_rev = VersionNumber::parse(&mut msg)?;
                 let fw_rev = VersionNumber::parse(&mut msg)?;
-                IoAttachEvent::AttachedIo { hw_rev, fw_rev }
+                IoAttachEvent::AttachedIo {
+                    io_type_id,
+                    hw_rev,
+                    fw_rev,
+                }
             }
             Event::AttachedVirtualIo => {
+                let io_type_id = ok!(IoTypeId::from_u16(next_u16!(msg)));
                 let port_a = next!(msg);
                 let port_b = next!(msg);
-                IoAttachEvent::AttachedVirtualIo { port_a, port_b }
+                IoAttachEvent::AttachedVirtualIo {
+                    io_type_id,
+                    port_a,
+                    port_b,
+                }
             }
         })
     }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        use IoAttachEvent::*;
+        match self {
+            DetachedIo => vec![Event::DetachedIo as u8],
+            AttachedIo {
+                io_type_id,
+                hw_rev,
+                fw_rev,
+            } => {
+                let mut msg = vec![Event::AttachedIo as u8];
+                msg.extend_from_slice(&(*io_type_id as u16).to_le_bytes());
+                msg.extend_from_slice(&hw_rev.serialise());
+                msg.extend_from_slice(&fw_rev.serialise());
+                msg
+            }
+            AttachedVirtualIo {
+                io_type_id,
+                port_a,
+                port_b,
+            } => {
+                let mut msg = vec![Event::AttachedVirtualIo as u8];
+                msg.extend_from_slice(&(*io_type_id as u16).to_le_bytes());
+                msg.push(*port_a);
+                msg.push(*port_b);
+                msg
+            }
+        }
+    }
 }
 
 /// One observed version number (for a large motor) is 0x1000002f,
@@ -679,28 +1028,49 @@ pub enum IoTypeId {
     ExternalMotor = 0x0026,
     InternalMotor = 0x0027,
     InternalTilt = 0x0028,
+    DuploTrainBaseMotor = 0x0029,
+    DuploTrainBaseSpeaker = 0x002a,
+    DuploTrainBaseColorSensor = 0x002b,
+    DuploTrainBaseSpeedometer = 0x002c,
+    TechnicLargeLinearMotor = 0x002e,
+    TechnicXlargeLinearMotor = 0x002f,
+    TechnicMediumAngularMotor = 0x0030,
+    TechnicLargeAngularMotor = 0x0031,
+    TechnicMediumHubGestSensor = 0x0036,
+    RemoteControlButton = 0x0037,
+    RemoteControlRssi = 0x0038,
+    TechnicMediumHubAccelerometer = 0x0039,
+    TechnicMediumHubGyroSensor = 0x003a,
+    TechnicMediumHubTiltSensor = 0x003b,
+    TechnicMediumHubTemperatureSensor = 0x003c,
+    TechnicColorSensor = 0x003d,
+    TechnicDistanceSensor = 0x003e,
+    TechnicForceSensor = 0x003f,
+    MarioAccelerometer = 0x0047,
+    MarioBarcodeSensor = 0x0049,
+    MarioPantsSensor = 0x004a,
+    TechnicMediumAngularMotorGrey = 0x004b,
+    TechnicLargeAngularMotorGrey = 0x004c,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Parse, Serialise)]
+#[lpu(message_type = "GenericErrorMessages")]
 pub struct ErrorMessageFormat {
-    command_type: u8,
-    error_code: ErrorCode,
+    pub command_type: MessageType,
+    pub error_code: ErrorCode,
 }
 
 impl ErrorMessageFormat {
-    pub fn parse<'a>(mut msg: impl Iterator<Item = &'a u8>) -> Result<Self> {
-        trace!("ErrorMessageFormat");
-        let command_type = next!(msg);
-        let error_code = ErrorCode::parse(&mut msg)?;
-        Ok(Self {
-            command_type,
-            error_code,
-        })
+    /// Ack and Mack acknowledge a command rather than reject it
+    pub fn is_error(&self) -> bool {
+        !matches!(self.error_code, ErrorCode::Ack | ErrorCode::Mack)
     }
 }
 
 #[repr(u8)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse, Serialise,
+)]
 pub enum ErrorCode {
     Ack = 0x01,
     Mack = 0x02,
@@ -730,16 +1100,16 @@ pub enum ErrorCode {
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum NetworkCommand {
-    ConnectionRequest(ButtonState),
+    ConnectionRequest(ConnectionButton),
     FamilyRequest,
     FamilySet(NetworkFamily),
-    JoinDenied(),
-    GetFamily(),
+    JoinDenied,
+    GetFamily,
     Family(NetworkFamily),
-    GetSubfamily(),
+    GetSubfamily,
     Subfamily(NetworkSubFamily),
     SubfamilySet(NetworkSubFamily),
-    GetExtendedFamily(),
+    GetExtendedFamily,
     ExtendedFamily {
         family: NetworkFamily,
         subfamily: NetworkSubFamily,
@@ -748,7 +1118,7 @@ pub enum NetworkCommand {
         family: NetworkFamily,
         subfamily: NetworkSubFamily,
     },
-    ResetLongPressTiming(),
+    ResetLongPressTiming,
 }
 
 impl NetworkCommand {
@@ -758,7 +1128,7 @@ impl NetworkCommand {
 
         Ok(match command_type {
             HwNetworkCommandType::ConnectionRequest => {
-                let button = ButtonState::parse(&mut msg)?;
+                let button = ConnectionButton::parse(&mut msg)?;
                 ConnectionRequest(button)
             }
             HwNetworkCommandType::FamilyRequest => FamilyRequest,
@@ -766,19 +1136,13 @@ impl NetworkCommand {
                 let fam = NetworkFamily::parse(&mut msg)?;
                 FamilySet(fam)
             }
-            HwNetworkCommandType::JoinDenied => {
-                todo!()
-            }
-            HwNetworkCommandType::GetFamily => {
-                todo!()
-            }
+            HwNetworkCommandType::JoinDenied => JoinDenied,
+            HwNetworkCommandType::GetFamily => GetFamily,
             HwNetworkCommandType::Family => {
                 let fam = NetworkFamily::parse(&mut msg)?;
                 Family(fam)
             }
-            HwNetworkCommandType::GetSubfamily => {
-                todo!()
-            }
+            HwNetworkCommandType::GetSubfamily => GetSubfamily,
             HwNetworkCommandType::Subfamily => {
                 let fam = NetworkSubFamily::parse(&mut msg)?;
                 Subfamily(fam)
@@ -787,9 +1151,7 @@ impl NetworkCommand {
                 let fam = NetworkSubFamily::parse(&mut msg)?;
                 SubfamilySet(fam)
             }
-            HwNetworkCommandType::GetExtendedFamily => {
-                todo!()
-            }
+            HwNetworkCommandType::GetExtendedFamily => GetExtendedFamily,
             HwNetworkCommandType::ExtendedFamily => {
                 // Bit 7 | sss | ffff
                 let byte = next!(msg);
@@ -808,15 +1170,83 @@ impl NetworkCommand {
                 let subfamily = ok!(NetworkSubFamily::from_u8(sub_bytes));
                 ExtendedFamilySet { family, subfamily }
             }
-            HwNetworkCommandType::ResetLongPressTiming => {
-                todo!()
-            }
+            HwNetworkCommandType::ResetLongPressTiming => ResetLongPressTiming,
         })
     }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        use NetworkCommand::*;
+        let mut msg = vec![0, 0, MessageType::HwNetworkCommands as u8];
+        match self {
+            ConnectionRequest(button) => msg.extend_from_slice(&[
+                HwNetworkCommandType::ConnectionRequest as u8,
+                *button as u8,
+            ]),
+            FamilyRequest => {
+                msg.push(HwNetworkCommandType::FamilyRequest as u8)
+            }
+            FamilySet(fam) => msg.extend_from_slice(&[
+                HwNetworkCommandType::FamilySet as u8,
+                *fam as u8,
+            ]),
+            JoinDenied => msg.push(HwNetworkCommandType::JoinDenied as u8),
+            GetFamily => msg.push(HwNetworkCommandType::GetFamily as u8),
+            Family(fam) => msg.extend_from_slice(&[
+                HwNetworkCommandType::Family as u8,
+                *fam as u8,
+            ]),
+            GetSubfamily => msg.push(HwNetworkCommandType::GetSubfamily as u8),
+            Subfamily(fam) => msg.extend_from_slice(&[
+                HwNetworkCommandType::Subfamily as u8,
+                *fam as u8,
+            ]),
+            SubfamilySet(fam) => msg.extend_from_slice(&[
+                HwNetworkCommandType::SubfamilySet as u8,
+                *fam as u8,
+            ]),
+            GetExtendedFamily => {
+                msg.push(HwNetworkCommandType::GetExtendedFamily as u8)
+            }
+            ExtendedFamily { family, subfamily } => msg.extend_from_slice(&[
+                HwNetworkCommandType::ExtendedFamily as u8,
+                Self::extended_family_byte(*family, *subfamily),
+            ]),
+            ExtendedFamilySet { family, subfamily } => {
+                msg.extend_from_slice(&[
+                    HwNetworkCommandType::ExtendedFamilySet as u8,
+                    Self::extended_family_byte(*family, *subfamily),
+                ])
+            }
+            ResetLongPressTiming => {
+                msg.push(HwNetworkCommandType::ResetLongPressTiming as u8)
+            }
+        }
+        msg
+    }
+
+    /// Bit 7 | sss | ffff
+    fn extended_family_byte(
+        family: NetworkFamily,
+        subfamily: NetworkSubFamily,
+    ) -> u8 {
+        ((subfamily as u8 & 0x7) << 4) | (family as u8 & 0x0f)
+    }
+}
+
+/// State of the hub's button in a connection request
+#[repr(u8)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse, Serialise,
+)]
+pub enum ConnectionButton {
+    Released = 0x00,
+    Pressed = 0x01,
 }
 
 #[repr(u8)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse, Serialise,
+)]
 pub enum ButtonState {
     Pressed = 2,
     Released = 0,
@@ -826,7 +1256,9 @@ pub enum ButtonState {
 }
 
 #[repr(u8)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse, Serialise,
+)]
 pub enum NetworkFamily {
     Green = 0x01,
     Yellow = 0x02,
@@ -840,7 +1272,9 @@ pub enum NetworkFamily {
 }
 
 #[repr(u8)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse, Serialise,
+)]
 pub enum NetworkSubFamily {
     OneFlash = 0x01,
     TwoFlashes = 0x02,
@@ -852,59 +1286,43 @@ pub enum NetworkSubFamily {
 }
 
 #[repr(u8)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse, Serialise,
+)]
 pub enum LockStatus {
     Ok = 0x00,
     NotLocked = 0xff,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Parse, Serialise)]
+#[lpu(message_type = "PortInformationRequest")]
 pub struct InformationRequest {
     port_id: u8,
     information_type: InformationType,
 }
 
-impl InformationRequest {
-    pub fn parse<'a>(mut msg: impl Iterator<Item = &'a u8>) -> Result<Self> {
-        let port_id = next!(msg);
-        let information_type = InformationType::parse(&mut msg)?;
-        Ok(InformationRequest {
-            port_id,
-            information_type,
-        })
-    }
-}
-
 #[repr(u8)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse, Serialise,
+)]
 pub enum InformationType {
     PortValue = 0x00,
     ModeInfo = 0x01,
     PossibleModeCombinations = 0x02,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Parse, Serialise)]
+#[lpu(message_type = "PortModeInformationRequest")]
 pub struct ModeInformationRequest {
-    port_id: u8,
-    mode: u8,
-    information_type: ModeInformationType,
-}
-
-impl ModeInformationRequest {
-    pub fn parse<'a>(mut msg: impl Iterator<Item = &'a u8>) -> Result<Self> {
-        let port_id = next!(msg);
-        let mode = next!(msg);
-        let information_type = ModeInformationType::parse(&mut msg)?;
-        Ok(Self {
-            port_id,
-            mode,
-            information_type,
-        })
-    }
+    pub(crate) port_id: u8,
+    pub(crate) mode: u8,
+    pub(crate) information_type: ModeInformationType,
 }
 
 #[repr(u8)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse, Serialise,
+)]
 pub enum ModeInformationType {
     Name = 0x00,
     Raw = 0x01,
@@ -918,7 +1336,8 @@ pub enum ModeInformationType {
     ValueFormat = 0x80,
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Parse, Serialise)]
+#[lpu(message_type = "PortInputFormatSetupSingle")]
 pub struct InputSetupSingle {
     pub(crate) port_id: u8,
     pub(crate) mode: u8,
@@ -926,67 +1345,42 @@ pub struct InputSetupSingle {
     pub(crate) notification_enabled: bool,
 }
 
-impl InputSetupSingle {
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InputSetupCombined {
+    pub port_id: u8,
+    pub subcommand: InputSetupCombinedSubcommand,
+}
+
+impl InputSetupCombined {
     pub fn parse<'a>(mut msg: impl Iterator<Item = &'a u8>) -> Result<Self> {
         let port_id = next!(msg);
-        let mode = next!(msg);
-        let delta = next_u32!(msg);
-        let notif_byte = next!(msg);
-        let notification_enabled = match notif_byte {
-            0x00 => false,
-            0x01 => true,
-            b => {
-                return Err(Error::ParseError(format!(
-                    "Invalid notification enabled state {:x}",
-                    b
-                )))
-            }
-        };
-        Ok(Self {
+        let subcommand = InputSetupCombinedSubcommand::parse(&mut msg)?;
+        Ok(InputSetupCombined {
             port_id,
-            mode,
-            delta,
-            notification_enabled,
+            subcommand,
         })
     }
 
     pub fn serialise(&self) -> Vec<u8> {
-        let mut msg = Vec::with_capacity(10);
-        msg.extend_from_slice(&[
+        let mut msg = vec![
             0,
             0,
-            MessageType::PortInputFormatSetupSingle as u8,
+            MessageType::PortInputFormatSetupCombinedmode as u8,
             self.port_id,
-            self.mode,
-        ]);
-        msg.extend_from_slice(&self.delta.to_le_bytes());
-        msg.push(self.notification_enabled as u8);
+        ];
+        msg.extend_from_slice(&self.subcommand.serialise());
         msg
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-pub struct InputSetupCombined {
-    port_id: u8,
-    subcommand: InputSetupCombinedSubcommand,
-}
-
-impl InputSetupCombined {
-    pub fn parse<'a>(mut msg: impl Iterator<Item = &'a u8>) -> Result<Self> {
-        let port_id = next!(msg);
-        let subcommand = InputSetupCombinedSubcommand::parse(&mut msg)?;
-        Ok(InputSetupCombined {
-            port_id,
-            subcommand,
-        })
-    }
-}
-
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum InputSetupCombinedSubcommand {
+    /// Each mode_dataset entry is a mode in the high nibble and a dataset
+    /// index in the low nibble, e.g. 0x21 for mode 2, dataset 1. Combined
+    /// mode values are reported in the order of this list.
     SetModeanddatasetCombinations {
         combination_index: u8,
-        mode_dataset: [u8; 8],
+        mode_dataset: Vec<u8>,
     },
     LockLpf2DeviceForSetup,
     UnlockAndStartMultiEnabled,
@@ -1003,10 +1397,7 @@ impl InputSetupCombinedSubcommand {
         Ok(match comm {
             PortInputFormatSetupSubCommand::SetModeanddatasetCombinations => {
                 let combination_index = next!(msg);
-                let mut mode_dataset = [0_u8; 8];
-                for ele in mode_dataset.iter_mut() {
-                    *ele = next!(msg);
-                }
+                let mode_dataset = msg.cloned().collect();
                 SetModeanddatasetCombinations {
                     combination_index,
                     mode_dataset,
@@ -1026,6 +1417,39 @@ impl InputSetupCombinedSubcommand {
             PortInputFormatSetupSubCommand::ResetSensor => ResetSensor,
         })
     }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        use InputSetupCombinedSubcommand::*;
+        match self {
+            SetModeanddatasetCombinations {
+                combination_index,
+                mode_dataset,
+            } => {
+                let mut msg = vec![
+                    PortInputFormatSetupSubCommand::SetModeanddatasetCombinations
+                        as u8,
+                    *combination_index,
+                ];
+                msg.extend_from_slice(mode_dataset);
+                msg
+            }
+            LockLpf2DeviceForSetup => vec![
+                PortInputFormatSetupSubCommand::LockLpf2DeviceForSetup as u8,
+            ],
+            UnlockAndStartMultiEnabled => vec![
+                PortInputFormatSetupSubCommand::UnlockAndStartMultiEnabled
+                    as u8,
+            ],
+            UnlockAndStartMultiDisabled => vec![
+                PortInputFormatSetupSubCommand::UnlockAndStartMultiDisabled
+                    as u8,
+            ],
+            NotUsed => vec![PortInputFormatSetupSubCommand::NotUsed as u8],
+            ResetSensor => {
+                vec![PortInputFormatSetupSubCommand::ResetSensor as u8]
+            }
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -1043,6 +1467,13 @@ impl PortInformationValue {
             information_type,
         })
     }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        let mut msg =
+            vec![0, 0, MessageType::PortInformation as u8, self.port_id];
+        msg.extend_from_slice(&self.information_type.serialise());
+        msg
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -1086,6 +1517,28 @@ impl PortInformationType {
             ))),
         }
     }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        use PortInformationType::*;
+        match self {
+            ModeInfo {
+                capabilities,
+                mode_count,
+                input_modes,
+                output_modes,
+            } => {
+                let mut msg = vec![1, capabilities.0, *mode_count];
+                msg.extend_from_slice(&input_modes.to_le_bytes());
+                msg.extend_from_slice(&output_modes.to_le_bytes());
+                msg
+            }
+            PossibleModeCombinations(combinations) => {
+                let mut msg = vec![2];
+                msg.extend_from_slice(combinations);
+                msg
+            }
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -1099,9 +1552,9 @@ impl PortCapabilities {
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct PortModeInformationValue {
-    port_id: u8,
-    mode: u8,
-    information_type: PortModeInformationType,
+    pub(crate) port_id: u8,
+    pub(crate) mode: u8,
+    pub(crate) information_type: PortModeInformationType,
 }
 
 impl PortModeInformationValue {
@@ -1115,6 +1568,18 @@ impl PortModeInformationValue {
             information_type,
         })
     }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        let mut msg = vec![
+            0,
+            0,
+            MessageType::PortModeInformation as u8,
+            self.port_id,
+            self.mode,
+        ];
+        msg.extend_from_slice(&self.information_type.serialise());
+        msg
+    }
 }
 
 #[repr(u8)]
@@ -1200,16 +1665,7 @@ impl PortModeInformationType {
             }
             128 => {
                 // value format is the struct format
-                let number_of_datasets = next!(msg);
-                let dataset_type = DatasetType::parse(&mut msg)?;
-                let total_figures = next!(msg);
-                let decimals = next!(msg);
-                ValueFormat(ValueFormatType {
-                    number_of_datasets,
-                    dataset_type,
-                    total_figures,
-                    decimals,
-                })
+                ValueFormat(ValueFormatType::parse(&mut msg)?)
             }
             t => {
                 return Err(Error::ParseError(format!(
@@ -1219,14 +1675,66 @@ impl PortModeInformationType {
             }
         })
     }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        use PortModeInformationType::*;
+        match self {
+            Name(name) => {
+                let mut msg = vec![ModeInformationType::Name as u8];
+                msg.extend_from_slice(name);
+                msg
+            }
+            RawRange { min, max } => {
+                Self::serialise_range(ModeInformationType::Raw, *min, *max)
+            }
+            PctRange { min, max } => {
+                Self::serialise_range(ModeInformationType::Pct, *min, *max)
+            }
+            SiRange { min, max } => {
+                Self::serialise_range(ModeInformationType::Si, *min, *max)
+            }
+            Symbol(sym) => {
+                let mut msg = vec![ModeInformationType::Symbol as u8];
+                msg.extend_from_slice(sym);
+                msg
+            }
+            Mapping { input, output } => {
+                vec![ModeInformationType::Mapping as u8, input.0, output.0]
+            }
+            MotorBias(bias) => {
+                vec![ModeInformationType::MotorBias as u8, *bias]
+            }
+            CapabilityBits(bits) => {
+                let mut msg = vec![ModeInformationType::CapabilityBits as u8];
+                msg.extend_from_slice(bits);
+                msg
+            }
+            ValueFormat(format) => {
+                let mut msg = vec![ModeInformationType::ValueFormat as u8];
+                msg.extend_from_slice(&format.serialise());
+                msg
+            }
+        }
+    }
+
+    fn serialise_range(
+        info_type: ModeInformationType,
+        min: f32,
+        max: f32,
+    ) -> Vec<u8> {
+        let mut msg = vec![info_type as u8];
+        msg.extend_from_slice(&min.to_le_bytes());
+        msg.extend_from_slice(&max.to_le_bytes());
+        msg
+    }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Parse, Serialise)]
 pub struct ValueFormatType {
-    number_of_datasets: u8,
-    dataset_type: DatasetType,
-    total_figures: u8,
-    decimals: u8,
+    pub number_of_datasets: u8,
+    pub dataset_type: DatasetType,
+    pub total_figures: u8,
+    pub decimals: u8,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -1240,7 +1748,9 @@ impl MappingValue {
 }
 
 #[repr(u8)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse, Serialise,
+)]
 pub enum DatasetType {
     Bits8 = 0b00,
     Bits16 = 0b01,
@@ -1256,45 +1766,236 @@ pub enum TypedValue {
     Float(f32),
 }
 
+impl TypedValue {
+    pub fn parse<'a>(
+        dataset_type: DatasetType,
+        mut msg: impl Iterator<Item = &'a u8>,
+    ) -> Result<Self> {
+        Ok(match dataset_type {
+            DatasetType::Bits8 => TypedValue::Bits8(next!(msg)),
+            DatasetType::Bits16 => TypedValue::Bits16(next_u16!(msg)),
+            DatasetType::Bits32 => TypedValue::Bits32(next_u32!(msg)),
+            DatasetType::Float => TypedValue::Float(next_f32!(msg)),
+        })
+    }
+}
+
+/// Record of the input mode that each port is currently reporting in and
+/// of the value format of each port mode. Port value notifications don't
+/// say how long each value is, so one of these should be kept per hub
+/// connection and updated with every notification received from the hub.
+#[derive(Clone, Debug, Default)]
+pub struct ValueFormatRegistry {
+    input_modes: HashMap<u8, u8>,
+    value_formats: HashMap<(u8, u8), ValueFormatType>,
+    combinations: HashMap<(u8, u8), Vec<u8>>,
+    combination_indices: HashMap<u8, u8>,
+}
+
+impl ValueFormatRegistry {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Record the input mode from a PortInputFormatSingle acknowledgement,
+    /// the value format from a PortModeInformation reply, or the
+    /// mode/dataset combination in use from a PortInputFormatCombinedmode
+    /// acknowledgement. Combined mode setups are sent by us rather than the
+    /// hub, so outgoing PortInputFormatSetupCombinedmode messages should be
+    /// passed in here as well. Any other message is ignored.
+    pub fn update(&mut self, msg: &NotificationMessage) {
+        match msg {
+            NotificationMessage::PortInputFormatSingle(fmt) => {
+                self.set_input_mode(fmt.port_id, fmt.mode);
+            }
+            NotificationMessage::PortInputFormatSetupCombinedmode(
+                InputSetupCombined {
+                    port_id,
+                    subcommand:
+                        InputSetupCombinedSubcommand::SetModeanddatasetCombinations {
+                            combination_index,
+                            mode_dataset,
+                        },
+                },
+            ) => {
+                self.set_combination(
+                    *port_id,
+                    *combination_index,
+                    mode_dataset.clone(),
+                );
+            }
+            NotificationMessage::PortInputFormatCombinedmode(fmt) => {
+                self.combination_indices
+                    .insert(fmt.port_id, fmt.combination_index);
+            }
+            NotificationMessage::PortModeInformation(
+                PortModeInformationValue {
+                    port_id,
+                    mode,
+                    information_type:
+                        PortModeInformationType::ValueFormat(format),
+                },
+            ) => {
+                self.set_value_format(*port_id, *mode, *format);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn set_input_mode(&mut self, port_id: u8, mode: u8) {
+        self.input_modes.insert(port_id, mode);
+    }
+
+    pub fn input_mode(&self, port_id: u8) -> Option<u8> {
+        self.input_modes.get(&port_id).copied()
+    }
+
+    pub fn set_value_format(
+        &mut self,
+        port_id: u8,
+        mode: u8,
+        format: ValueFormatType,
+    ) {
+        self.value_formats.insert((port_id, mode), format);
+    }
+
+    pub fn value_format(
+        &self,
+        port_id: u8,
+        mode: u8,
+    ) -> Option<ValueFormatType> {
+        self.value_formats.get(&(port_id, mode)).copied()
+    }
+
+    /// Value format of the mode the port is currently reporting in
+    pub fn current_value_format(&self, port_id: u8) -> Option<ValueFormatType> {
+        self.value_format(port_id, self.input_mode(port_id)?)
+    }
+
+    /// Record a mode/dataset combination for the port and make it the one
+    /// in use, as the hub does when a combination is set up.
+    pub fn set_combination(
+        &mut self,
+        port_id: u8,
+        combination_index: u8,
+        mode_dataset: Vec<u8>,
+    ) {
+        self.combinations
+            .insert((port_id, combination_index), mode_dataset);
+        self.combination_indices.insert(port_id, combination_index);
+    }
+
+    /// Mode/dataset combination the port is currently reporting in
+    pub fn current_combination(&self, port_id: u8) -> Option<&[u8]> {
+        let index = self.combination_indices.get(&port_id)?;
+        self.combinations
+            .get(&(port_id, *index))
+            .map(|c| c.as_slice())
+    }
+}
+
 /// The PortValueSingleFormat is a list of port id & value pairs, except
 /// that the values may be different lengths (u8, u16, u32, f32) depending
-/// on the port configuration. For now we just save the payload and then
-/// later on will provide a method to split it out into port-value pairs
-/// based on a separate port type mapping
-#[derive(Clone, Debug, PartialEq, Eq)]
+/// on the port configuration. We save the payload as it stands and split
+/// it into port-value pairs with `process`, based on the port formats
+/// recorded in a ValueFormatRegistry
+#[derive(Clone, Debug, PartialEq, Eq, Parse, Serialise)]
+#[lpu(message_type = "PortValueSingle")]
 pub struct PortValueSingleFormat {
-    values: Vec<u8>,
+    pub(crate) payload: Vec<u8>,
 }
 
 impl PortValueSingleFormat {
-    pub fn parse<'a>(msg: impl Iterator<Item = &'a u8>) -> Result<Self> {
-        let values = msg.cloned().collect();
-        Ok(PortValueSingleFormat { values })
+    /// Split the payload into the datasets reported by each port. Fails if
+    /// a port's current input mode or that mode's value format is not in
+    /// the registry, since the rest of the payload can't be located.
+    pub fn process(
+        &self,
+        registry: &ValueFormatRegistry,
+    ) -> Result<HashMap<u8, Vec<TypedValue>>> {
+        let mut ports = HashMap::new();
+        let mut msg = self.payload.iter();
+        while let Some(&port_id) = msg.next() {
+            let format =
+                registry.current_value_format(port_id).ok_or_else(|| {
+                    Error::ParseError(format!(
+                        "No value format known for port {}",
+                        port_id
+                    ))
+                })?;
+            let values = (0..format.number_of_datasets)
+                .map(|_| TypedValue::parse(format.dataset_type, &mut msg))
+                .collect::<Result<Vec<_>>>()?;
+            ports.insert(port_id, values);
+        }
+        Ok(ports)
     }
+}
 
-    pub fn process(&self, _type_mapping: ()) -> HashMap<u8, TypedValue> {
-        unimplemented!()
-    }
+/// A single dataset out of a combined mode value notification
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CombinedValue {
+    pub mode: u8,
+    pub dataset: u8,
+    pub value: TypedValue,
 }
 
-/// The PortValueCombinedFormat is some horrific set of pointers to
-/// values we should already have cached elsewhere. For now we save the
-/// raw data and leave parsing it for later.
-#[derive(Clone, Debug, PartialEq, Eq)]
+/// The PortValueCombinedFormat is a bit field pointing into the port's
+/// mode/dataset combination, followed by the values of the entries that
+/// are present. We save the raw data and decode it with `process`, based
+/// on the combination and value formats recorded in a ValueFormatRegistry.
+#[derive(Clone, Debug, PartialEq, Eq, Parse, Serialise)]
+#[lpu(message_type = "PortValueCombinedmode")]
 pub struct PortValueCombinedFormat {
-    port_id: u8,
-    data: Vec<u8>,
+    pub(crate) port_id: u8,
+    pub(crate) data: Vec<u8>,
 }
 
 impl PortValueCombinedFormat {
-    pub fn parse<'a>(mut msg: impl Iterator<Item = &'a u8>) -> Result<Self> {
-        let port_id = next!(msg);
-        let data = msg.cloned().collect();
-        Ok(Self { port_id, data })
+    /// Decode the values present, in combination order. Fails if the port's
+    /// combination or the value format of one of its modes is not in the
+    /// registry.
+    pub fn process(
+        &self,
+        registry: &ValueFormatRegistry,
+    ) -> Result<Vec<CombinedValue>> {
+        let mut msg = self.data.iter();
+        let pointer = next_u16!(msg);
+        let combination =
+            registry.current_combination(self.port_id).ok_or_else(|| {
+                Error::ParseError(format!(
+                    "No mode/dataset combination known for port {}",
+                    self.port_id
+                ))
+            })?;
+
+        let mut values = Vec::new();
+        for (i, entry) in combination.iter().enumerate().take(16) {
+            if pointer & (1 << i) == 0 {
+                continue;
+            }
+            let mode = entry >> 4;
+            let dataset = entry & 0x0f;
+            let format =
+                registry.value_format(self.port_id, mode).ok_or_else(|| {
+                    Error::ParseError(format!(
+                        "No value format known for port {} mode {}",
+                        self.port_id, mode
+                    ))
+                })?;
+            let value = TypedValue::parse(format.dataset_type, &mut msg)?;
+            values.push(CombinedValue {
+                mode,
+                dataset,
+                value,
+            });
+        }
+        Ok(values)
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Parse, Serialise)]
+#[lpu(message_type = "PortInputFormatSingle")]
 pub struct PortInputFormatSingleFormat {
     pub port_id: u8,
     pub mode: u8,
@@ -1302,28 +2003,8 @@ pub struct PortInputFormatSingleFormat {
     pub notification_enabled: bool,
 }
 
-impl PortInputFormatSingleFormat {
-    pub fn parse<'a>(mut msg: impl Iterator<Item = &'a u8>) -> Result<Self> {
-        let port_id = next!(msg);
-        let mode = next!(msg);
-        let delta = next_u32!(msg);
-        let notification_enabled = match next!(msg) {
-            0 => Ok(false),
-            1 => Ok(true),
-            v => Err(Error::ParseError(format!(
-                "Invalid notification enabled status {}",
-                v
-            ))),
-        }?;
-        Ok(Self {
-            port_id,
-            mode,
-            delta,
-            notification_enabled,
-        })
-    }
-}
-
+/// The control byte holds the multi-update flag in bit 7 and the
+/// combination index in bits 0-3
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct PortInputFormatCombinedFormat {
     port_id: u8,
@@ -1334,10 +2015,25 @@ pub struct PortInputFormatCombinedFormat {
 }
 
 impl PortInputFormatCombinedFormat {
+    pub fn new(
+        port_id: u8,
+        combination_index: u8,
+        multi_update: bool,
+        mode_dataset_combination_pointer: u16,
+    ) -> Self {
+        Self {
+            port_id,
+            control: (multi_update as u8) << 7 | (combination_index & 0x0f),
+            combination_index: combination_index & 0x0f,
+            multi_update,
+            mode_dataset_combination_pointer,
+        }
+    }
+
     pub fn parse<'a>(mut msg: impl Iterator<Item = &'a u8>) -> Result<Self> {
         let port_id = next!(msg);
         let control = next!(msg);
-        let combination_index = next!(msg);
+        let combination_index = control & 0x0f;
         let multi_update = (control >> 7) != 0;
         let mode_dataset_combination_pointer = next_u16!(msg);
 
@@ -1349,6 +2045,20 @@ impl PortInputFormatCombinedFormat {
             mode_dataset_combination_pointer,
         })
     }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        let mut msg = vec![
+            0,
+            0,
+            MessageType::PortInputFormatCombinedmode as u8,
+            self.port_id,
+            self.control,
+        ];
+        msg.extend_from_slice(
+            &self.mode_dataset_combination_pointer.to_le_bytes(),
+        );
+        msg
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -1378,6 +2088,18 @@ impl VirtualPortSetupFormat {
             ))),
         }
     }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        use VirtualPortSetupFormat::*;
+        let mut msg = vec![0, 0, MessageType::VirtualPortSetup as u8];
+        match self {
+            Disconnect { port_id } => msg.extend_from_slice(&[0, *port_id]),
+            Connect { port_a, port_b } => {
+                msg.extend_from_slice(&[1, *port_a, *port_b])
+            }
+        }
+        msg
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -1407,32 +2129,17 @@ impl PortOutputCommandFormat {
     }
 
     pub fn serialise(&self) -> Vec<u8> {
-        use PortOutputSubcommand::*;
-        match &self.subcommand {
-            StartSpeed {
-                speed,
-                max_power,
-                use_acc_profile,
-                use_dec_profile,
-            } => {
-                let profile =
-                    ((*use_acc_profile as u8) << 1) | (*use_dec_profile as u8);
-                let speed = speed.to_le_bytes()[0];
-                vec![
-                    0, // len
-                    0, // hub id
-                    MessageType::PortOutputCommand as u8,
-                    self.port_id,
-                    0x11,
-                    0x01,
-                    speed,
-                    max_power.to_u8(),
-                    profile,
-                ]
-            }
-            WriteDirectModeData(data) => data.serialise(self),
-            _ => todo!(),
-        }
+        let startup_and_completion =
+            self.startup_info.serialise(&self.completion_info);
+        let mut msg = vec![
+            0, // len
+            0, // hub id
+            MessageType::PortOutputCommand as u8,
+            self.port_id,
+            startup_and_completion,
+        ];
+        msg.extend_from_slice(&self.subcommand.serialise());
+        msg
     }
 }
 
@@ -1739,12 +2446,12 @@ impl PortOutputSubcommand {
                     right_position,
                 }
             }
-            50 => {
+            0x50 => {
                 // WriteDirect(Byte[0],Byte[0 + n])
                 let data = WriteDirectPayload::parse(&mut msg)?;
                 WriteDirect(data)
             }
-            51 => {
+            0x51 => {
                 // WriteDirectModeData(Mode, PayLoad[0] PayLoad [0 + n]
                 let data = WriteDirectModeDataPayload::parse(&mut msg)?;
                 WriteDirectModeData(data)
@@ -1757,6 +2464,194 @@ impl PortOutputSubcommand {
             }
         })
     }
+
+    /// Serialise the subcommand number and its parameters, i.e. everything
+    /// following the startup and completion byte
+    pub fn serialise(&self) -> Vec<u8> {
+        use PortOutputSubcommand::*;
+
+        let mut msg = Vec::new();
+        match self {
+            StartPower2 { power1, power2 } => {
+                msg.extend_from_slice(&[0x02, power1.to_u8(), power2.to_u8()]);
+            }
+            SetAccTime {
+                time,
+                profile_number,
+            } => {
+                msg.push(0x05);
+                msg.extend_from_slice(&time.to_le_bytes());
+                msg.push(*profile_number as u8);
+            }
+            SetDecTime {
+                time,
+                profile_number,
+            } => {
+                msg.push(0x06);
+                msg.extend_from_slice(&time.to_le_bytes());
+                msg.push(*profile_number as u8);
+            }
+            StartSpeed {
+                speed,
+                max_power,
+                use_acc_profile,
+                use_dec_profile,
+            } => {
+                msg.extend_from_slice(&[
+                    0x07,
+                    *speed as u8,
+                    max_power.to_u8(),
+                    Self::profile(*use_acc_profile, *use_dec_profile),
+                ]);
+            }
+            StartSpeed2 {
+                speed1,
+                speed2,
+                max_power,
+                use_acc_profile,
+                use_dec_profile,
+            } => {
+                msg.extend_from_slice(&[
+                    0x08,
+                    *speed1 as u8,
+                    *speed2 as u8,
+                    *max_power as u8,
+                    Self::profile(*use_acc_profile, *use_dec_profile),
+                ]);
+            }
+            StartSpeedForTime {
+                time,
+                speed,
+                max_power,
+                end_state,
+                use_acc_profile,
+                use_dec_profile,
+            } => {
+                msg.push(0x09);
+                msg.extend_from_slice(&time.to_le_bytes());
+                msg.extend_from_slice(&[
+                    *speed as u8,
+                    *max_power as u8,
+                    *end_state as u8,
+                    Self::profile(*use_acc_profile, *use_dec_profile),
+                ]);
+            }
+            StartSpeedForTime2 {
+                time,
+                speed_l,
+                speed_r,
+                max_power,
+                end_state,
+                use_acc_profile,
+                use_dec_profile,
+            } => {
+                msg.push(0x0a);
+                msg.extend_from_slice(&time.to_le_bytes());
+                msg.extend_from_slice(&[
+                    *speed_l as u8,
+                    *speed_r as u8,
+                    *max_power as u8,
+                    *end_state as u8,
+                    Self::profile(*use_acc_profile, *use_dec_profile),
+                ]);
+            }
+            StartSpeedForDegrees {
+                degrees,
+                speed,
+                max_power,
+                end_state,
+                use_acc_profile,
+                use_dec_profile,
+            } => {
+                msg.push(0x0b);
+                msg.extend_from_slice(&degrees.to_le_bytes());
+                msg.extend_from_slice(&[
+                    *speed as u8,
+                    *max_power as u8,
+                    *end_state as u8,
+                    Self::profile(*use_acc_profile, *use_dec_profile),
+                ]);
+            }
+            StartSpeedForDegrees2 {
+                degrees,
+                speed_l,
+                speed_r,
+                max_power,
+                end_state,
+                use_acc_profile,
+                use_dec_profile,
+            } => {
+                msg.push(0x0c);
+                msg.extend_from_slice(&degrees.to_le_bytes());
+                msg.extend_from_slice(&[
+                    *speed_l as u8,
+                    *speed_r as u8,
+                    *max_power as u8,
+                    *end_state as u8,
+                    Self::profile(*use_acc_profile, *use_dec_profile),
+                ]);
+            }
+            GotoAbsolutePosition {
+                abs_pos,
+                speed,
+                max_power,
+                end_state,
+                use_acc_profile,
+                use_dec_profile,
+            } => {
+                msg.push(0x0d);
+                msg.extend_from_slice(&abs_pos.to_le_bytes());
+                msg.extend_from_slice(&[
+                    *speed as u8,
+                    *max_power as u8,
+                    *end_state as u8,
+                    Self::profile(*use_acc_profile, *use_dec_profile),
+                ]);
+            }
+            GotoAbsolutePosition2 {
+                abs_pos1,
+                abs_pos2,
+                speed,
+                max_power,
+                end_state,
+                use_acc_profile,
+                use_dec_profile,
+            } => {
+                msg.push(0x0e);
+                msg.extend_from_slice(&abs_pos1.to_le_bytes());
+                msg.extend_from_slice(&abs_pos2.to_le_bytes());
+                msg.extend_from_slice(&[
+                    *speed as u8,
+                    *max_power as u8,
+                    *end_state as u8,
+                    Self::profile(*use_acc_profile, *use_dec_profile),
+                ]);
+            }
+            PresetEncoder2 {
+                left_position,
+                right_position,
+            } => {
+                msg.push(0x14);
+                msg.extend_from_slice(&left_position.to_le_bytes());
+                msg.extend_from_slice(&right_position.to_le_bytes());
+            }
+            WriteDirect(data) => {
+                msg.push(0x50);
+                msg.extend_from_slice(&data.serialise());
+            }
+            WriteDirectModeData(data) => {
+                msg.push(0x51);
+                msg.extend_from_slice(&data.serialise());
+            }
+        }
+        msg
+    }
+
+    /// UseProfile: bit 0 selects the acceleration profile and bit 1 the
+    /// deceleration profile
+    fn profile(use_acc_profile: bool, use_dec_profile: bool) -> u8 {
+        (use_acc_profile as u8) | ((use_dec_profile as u8) << 1)
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -1809,8 +2704,50 @@ pub enum WriteDirectPayload {
 }
 
 impl WriteDirectPayload {
-    pub fn parse<'a>(_msg: impl Iterator<Item = &'a u8>) -> Result<Self> {
-        todo!()
+    /// Device command for the factory calibration of the tilt sensor,
+    /// which shares its number with the sensor's CALIB mode
+    const TILT_FACTORY_CALIBRATION: u8 = 0x07;
+    /// Device command for a hardware reset
+    const HARDWARE_RESET: u8 = 0xd4;
+
+    pub fn parse<'a>(mut msg: impl Iterator<Item = &'a u8>) -> Result<Self> {
+        use WriteDirectPayload::*;
+        match next!(msg) {
+            Self::TILT_FACTORY_CALIBRATION => {
+                let orientation = CalibrationOrientation::parse(&mut msg)?;
+                let pass_code = String::from_utf8(msg.copied().collect())
+                    .map_err(|e| {
+                        Error::ParseError(format!("Invalid pass code: {}", e))
+                    })?;
+                Ok(TiltFactoryCalibration {
+                    orientation,
+                    pass_code,
+                })
+            }
+            Self::HARDWARE_RESET => Ok(HardwareReset),
+            c => Err(Error::ParseError(format!(
+                "Unknown write direct command {:#04x}",
+                c
+            ))),
+        }
+    }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        use WriteDirectPayload::*;
+        match self {
+            TiltFactoryCalibration {
+                orientation,
+                pass_code,
+            } => {
+                let mut msg = vec![
+                    Self::TILT_FACTORY_CALIBRATION,
+                    *orientation as i8 as u8,
+                ];
+                msg.extend_from_slice(pass_code.as_bytes());
+                msg
+            }
+            HardwareReset => vec![Self::HARDWARE_RESET],
+        }
     }
 }
 
@@ -1828,7 +2765,9 @@ impl CalibrationOrientation {
 }
 
 #[repr(u8)]
-#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, FromPrimitive, Parse, Serialise,
+)]
 pub enum EndState {
     Float = 0,
     Hold = 126,
@@ -1857,17 +2796,29 @@ impl WriteDirectModeDataPayload {
     pub fn parse<'a>(mut msg: impl Iterator<Item = &'a u8>) -> Result<Self> {
         use WriteDirectModeDataPayload::*;
 
+        // The mode is the device mode being written to, so these are the
+        // mode numbers of the relevant devices rather than the subcommand
+        // numbers from the documentation. SetRgbColorNo writes to mode
+        // 0x00 of the hub LED and is indistinguishable on the wire from
+        // StartPower, so it is always parsed as the latter.
         let mode = next!(msg);
         Ok(match mode {
-            0x01 => {
+            0x00 => {
                 // StartPower(Power)
                 let power = Power::parse(&mut msg)?;
                 StartPower(power)
             }
-            0x02 => {
-                // PresetEncoder(Position)
-                let position = next_i32!(msg);
-                PresetEncoder(position)
+            0x01 => {
+                // SetRgbColors(RedColor, GreenColor, BlueColor)
+                let red = next!(msg);
+                let green = next!(msg);
+                let blue = next!(msg);
+                SetRgbColors { red, green, blue }
+            }
+            0x02 => {
+                // PresetEncoder(Position)
+                let position = next_i32!(msg);
+                PresetEncoder(position)
             }
             0x03 => {
                 // TiltImpactPreset(PresetValue)
@@ -1891,18 +2842,6 @@ impl WriteDirectModeDataPayload {
                     bump_holdoff,
                 }
             }
-            0x08 => {
-                // SetRgbColorNo(ColorNo)
-                let col = next_i8!(msg);
-                SetRgbColorNo(col)
-            }
-            0x09 => {
-                // SetRgbColors(RedColor, GreenColor, BlueColor)
-                let red = next!(msg);
-                let green = next!(msg);
-                let blue = next!(msg);
-                SetRgbColors { red, green, blue }
-            }
             m => {
                 return Err(Error::ParseError(format!(
                     "Invalid write direct mode {}",
@@ -1912,42 +2851,49 @@ impl WriteDirectModeDataPayload {
         })
     }
 
-    pub fn serialise(&self, meta: &PortOutputCommandFormat) -> Vec<u8> {
+    /// Serialise the device mode and the data written to it
+    pub fn serialise(&self) -> Vec<u8> {
         use WriteDirectModeDataPayload::*;
+        let mut msg = Vec::new();
         match self {
+            StartPower(p) => {
+                // magic value from docs
+                msg.extend_from_slice(&[0x00, p.to_u8()]);
+            }
+            PresetEncoder(position) => {
+                msg.push(0x02);
+                msg.extend_from_slice(&position.to_le_bytes());
+            }
+            TiltImpactPreset(preset_value) => {
+                msg.push(0x03);
+                msg.extend_from_slice(&preset_value.to_le_bytes());
+            }
+            TiltConfigOrientation(orientation) => {
+                msg.extend_from_slice(&[0x05, *orientation as i8 as u8]);
+            }
+            TiltConfigImpact {
+                impact_threshold,
+                bump_holdoff,
+            } => {
+                msg.extend_from_slice(&[
+                    0x06,
+                    *impact_threshold as u8,
+                    *bump_holdoff as u8,
+                ]);
+            }
+            SetRgbColorNo(col) => {
+                msg.extend_from_slice(&[HubLedMode::Colour as u8, *col as u8]);
+            }
             SetRgbColors { red, green, blue } => {
-                let startup_and_completion =
-                    meta.startup_info.serialise(&meta.completion_info);
-                vec![
-                    0,
-                    0, // hub id
-                    MessageType::PortOutputCommand as u8,
-                    meta.port_id,
-                    startup_and_completion,
-                    0x51, // WriteDirect
+                msg.extend_from_slice(&[
                     HubLedMode::Rgb as u8,
                     *red,
                     *green,
                     *blue,
-                ]
+                ]);
             }
-            StartPower(p) => {
-                let startup_and_completion =
-                    meta.startup_info.serialise(&meta.completion_info);
-                let power = p.to_u8();
-                vec![
-                    0,
-                    0, // hub id
-                    MessageType::PortOutputCommand as u8,
-                    meta.port_id,
-                    startup_and_completion,
-                    0x51, // WriteDirect
-                    0x00, // magic value from docs
-                    power,
-                ]
-            }
-            _ => todo!(),
         }
+        msg
     }
 }
 
@@ -1971,9 +2917,9 @@ impl Orientation {
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct PortOutputCommandFeedbackFormat {
-    msg1: FeedbackMessage,
-    msg2: Option<FeedbackMessage>,
-    msg3: Option<FeedbackMessage>,
+    pub(crate) msg1: FeedbackMessage,
+    pub(crate) msg2: Option<FeedbackMessage>,
+    pub(crate) msg3: Option<FeedbackMessage>,
 }
 
 impl PortOutputCommandFeedbackFormat {
@@ -1983,16 +2929,25 @@ impl PortOutputCommandFeedbackFormat {
         let msg3 = FeedbackMessage::parse(&mut msg).ok();
         Ok(PortOutputCommandFeedbackFormat { msg1, msg2, msg3 })
     }
+
+    pub fn serialise(&self) -> Vec<u8> {
+        let mut msg = vec![0, 0, MessageType::PortOutputCommandFeedback as u8];
+        for feedback in [Some(self.msg1), self.msg2, self.msg3].iter().flatten()
+        {
+            msg.extend_from_slice(&feedback.serialise());
+        }
+        msg
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct FeedbackMessage {
-    port_id: u8,
-    empty_cmd_in_progress: bool,
-    empty_cmd_completed: bool,
-    discarded: bool,
-    idle: bool,
-    busy_full: bool,
+    pub(crate) port_id: u8,
+    pub(crate) empty_cmd_in_progress: bool,
+    pub(crate) empty_cmd_completed: bool,
+    pub(crate) discarded: bool,
+    pub(crate) idle: bool,
+    pub(crate) busy_full: bool,
 }
 
 impl FeedbackMessage {
@@ -2013,6 +2968,15 @@ impl FeedbackMessage {
             busy_full,
         })
     }
+
+    pub fn serialise(&self) -> [u8; 2] {
+        let bitfields = (self.empty_cmd_in_progress as u8)
+            | (self.empty_cmd_completed as u8) << 1
+            | (self.discarded as u8) << 2
+            | (self.idle as u8) << 3
+            | (self.busy_full as u8) << 4;
+        [self.port_id, bitfields]
+    }
 }
 
 #[cfg(test)]
@@ -2059,6 +3023,17 @@ mod test {
         for msg in msgs {
             let _notif = NotificationMessage::parse(msg).unwrap();
         }
+
+        let notif = NotificationMessage::parse(&[5, 0, 5, 0x81, 7]).unwrap();
+        let NotificationMessage::GenericErrorMessages(error) = notif else {
+            panic!("wrong type");
+        };
+        assert_eq!(error.command_type, MessageType::PortOutputCommand);
+        assert_eq!(error.error_code, ErrorCode::Overcurrent);
+        assert!(error.is_error());
+
+        // Not a message type
+        assert!(NotificationMessage::parse(&[5, 0, 5, 0x30, 5]).is_err());
     }
 
     /*#[test]
@@ -2312,9 +3287,974 @@ mod test {
                 subcommand,
             });
         let serialised = msg.serialise();
-        let correct = &mut [0, 0, 0x81, 1, 0x11, 0x01, 0x12, 0x34, 0x03];
+        let correct = &mut [0, 0, 0x81, 1, 0x10, 0x07, 0x12, 0x34, 0x03];
         correct[0] = correct.len() as u8;
 
         assert_eq!(&serialised, correct);
     }
+
+    #[test]
+    fn serialise_round_trip() {
+        init();
+        use NotificationMessage::*;
+
+        let version = VersionNumber {
+            major: 1,
+            minor: 7,
+            bugfix: 37,
+            build: 0x1510,
+        };
+        let feedback = FeedbackMessage {
+            port_id: 1,
+            empty_cmd_in_progress: false,
+            empty_cmd_completed: true,
+            discarded: false,
+            idle: true,
+            busy_full: false,
+        };
+        let msgs = [
+            HubProperties(HubProperty {
+                reference: HubPropertyReference::AdvertisingName,
+                operation: HubPropertyOperation::UpdateUpstream,
+                property: Some(HubPropertyValue::AdvertisingName(
+                    b"Technic Hub".to_vec(),
+                )),
+            }),
+            HubProperties(HubProperty {
+                reference: HubPropertyReference::BatteryVoltage,
+                operation: HubPropertyOperation::RequestUpdateDownstream,
+                property: None,
+            }),
+            HubActions(HubAction::SwitchOffHub),
+            HubAlerts(HubAlert::enable_updates(AlertType::LowVoltage)),
+            HubAlerts(HubAlert {
+                alert_type: AlertType::HighCurrent,
+                operation: AlertOperation::Update,
+                payload: Some(AlertPayload::Alert),
+            }),
+            HubAttachedIo(AttachedIo {
+                port: 0,
+                event: IoAttachEvent::AttachedIo {
+                    io_type_id: IoTypeId::TechnicXlargeLinearMotor,
+                    hw_rev: version,
+                    fw_rev: version,
+                },
+            }),
+            HubAttachedIo(AttachedIo {
+                port: 0,
+                event: IoAttachEvent::DetachedIo,
+            }),
+            HubAttachedIo(AttachedIo {
+                port: 16,
+                event: IoAttachEvent::AttachedVirtualIo {
+                    io_type_id: IoTypeId::TechnicLargeLinearMotor,
+                    port_a: 0,
+                    port_b: 1,
+                },
+            }),
+            GenericErrorMessages(ErrorMessageFormat {
+                command_type: MessageType::PortOutputCommand,
+                error_code: ErrorCode::CommandNotRecognized,
+            }),
+            HwNetworkCommands(NetworkCommand::ExtendedFamilySet {
+                family: NetworkFamily::Blue,
+                subfamily: NetworkSubFamily::ThreeFlashes,
+            }),
+            FwUpdateGoIntoBootMode(*b"LPF2-Boot"),
+            FwUpdateLockMemory([1, 2, 3, 4, 5, 6, 7, 8]),
+            FwUpdateLockStatusRequest,
+            FwLockStatus(LockStatus::NotLocked),
+            PortInformationRequest(InformationRequest {
+                port_id: 0,
+                information_type: InformationType::ModeInfo,
+            }),
+            PortModeInformationRequest(ModeInformationRequest {
+                port_id: 0,
+                mode: 2,
+                information_type: ModeInformationType::ValueFormat,
+            }),
+            PortInputFormatSetupSingle(InputSetupSingle {
+                port_id: 0,
+                mode: 2,
+                delta: 5,
+                notification_enabled: true,
+            }),
+            PortInputFormatSetupCombinedmode(InputSetupCombined {
+                port_id: 0,
+                subcommand:
+                    InputSetupCombinedSubcommand::SetModeanddatasetCombinations {
+                        combination_index: 0,
+                        mode_dataset: vec![0x10, 0x20, 0x30],
+                    },
+            }),
+            PortInputFormatSetupCombinedmode(InputSetupCombined {
+                port_id: 0,
+                subcommand:
+                    InputSetupCombinedSubcommand::UnlockAndStartMultiEnabled,
+            }),
+            PortInformation(PortInformationValue {
+                port_id: 0,
+                information_type: PortInformationType::ModeInfo {
+                    capabilities: PortCapabilities(0x0f),
+                    mode_count: 6,
+                    input_modes: 0x1e,
+                    output_modes: 0x01,
+                },
+            }),
+            PortInformation(PortInformationValue {
+                port_id: 0,
+                information_type: PortInformationType::PossibleModeCombinations(
+                    vec![0x0e, 0x00],
+                ),
+            }),
+            PortModeInformation(PortModeInformationValue {
+                port_id: 0,
+                mode: 1,
+                information_type: PortModeInformationType::Name(
+                    b"SPEED".to_vec(),
+                ),
+            }),
+            PortModeInformation(PortModeInformationValue {
+                port_id: 0,
+                mode: 1,
+                information_type: PortModeInformationType::SiRange {
+                    min: -100.0,
+                    max: 100.0,
+                },
+            }),
+            PortModeInformation(PortModeInformationValue {
+                port_id: 0,
+                mode: 1,
+                information_type: PortModeInformationType::Mapping {
+                    input: MappingValue(MappingValue::REL),
+                    output: MappingValue(MappingValue::REL),
+                },
+            }),
+            PortModeInformation(PortModeInformationValue {
+                port_id: 0,
+                mode: 2,
+                information_type: PortModeInformationType::ValueFormat(
+                    ValueFormatType {
+                        number_of_datasets: 1,
+                        dataset_type: DatasetType::Bits32,
+                        total_figures: 4,
+                        decimals: 0,
+                    },
+                ),
+            }),
+            PortValueSingle(PortValueSingleFormat {
+                payload: vec![0, 0x12, 0x34, 0x56, 0x78],
+            }),
+            PortValueCombinedmode(PortValueCombinedFormat {
+                port_id: 0,
+                data: vec![0x00, 0x07, 0x1a, 0x2b, 0x00, 0x00],
+            }),
+            PortInputFormatSingle(PortInputFormatSingleFormat {
+                port_id: 0,
+                mode: 2,
+                delta: 5,
+                notification_enabled: true,
+            }),
+            PortInputFormatCombinedmode(PortInputFormatCombinedFormat {
+                port_id: 0,
+                control: 0x81,
+                combination_index: 1,
+                multi_update: true,
+                mode_dataset_combination_pointer: 0x0007,
+            }),
+            VirtualPortSetup(VirtualPortSetupFormat::Connect {
+                port_a: 0,
+                port_b: 1,
+            }),
+            VirtualPortSetup(VirtualPortSetupFormat::Disconnect {
+                port_id: 16,
+            }),
+            PortOutputCommand(PortOutputCommandFormat {
+                port_id: 50,
+                startup_info: StartupInfo::ExecuteImmediately,
+                completion_info: CompletionInfo::CommandFeedback,
+                subcommand: PortOutputSubcommand::WriteDirectModeData(
+                    WriteDirectModeDataPayload::SetRgbColors {
+                        red: 0x12,
+                        green: 0x34,
+                        blue: 0x56,
+                    },
+                ),
+            }),
+            PortOutputCommandFeedback(PortOutputCommandFeedbackFormat {
+                msg1: feedback,
+                msg2: None,
+                msg3: None,
+            }),
+            PortOutputCommandFeedback(PortOutputCommandFeedbackFormat {
+                msg1: feedback,
+                msg2: Some(FeedbackMessage {
+                    port_id: 2,
+                    ..feedback
+                }),
+                msg3: Some(FeedbackMessage {
+                    port_id: 3,
+                    busy_full: true,
+                    ..feedback
+                }),
+            }),
+        ];
+
+        for msg in msgs {
+            let serialised = msg.serialise();
+            assert_eq!(serialised[0] as usize, serialised.len());
+            assert_eq!(serialised[2], msg.message_type());
+            let parsed = NotificationMessage::parse(&serialised).unwrap();
+            assert_eq!(parsed, msg);
+        }
+    }
+
+    #[test]
+    fn hub_property_operations() {
+        init();
+        use HubPropertyReference::*;
+
+        let test_cases: &[(HubProperty, &[u8])] = &[
+            (
+                HubProperty::set(HubPropertyValue::AdvertisingName(
+                    b"abc".to_vec(),
+                )),
+                &[8, 0, 0x01, 0x01, 0x01, b'a', b'b', b'c'],
+            ),
+            (HubProperty::enable_updates(Rssi), &[5, 0, 0x01, 0x05, 0x02]),
+            (
+                HubProperty::disable_updates(BatteryVoltage),
+                &[5, 0, 0x01, 0x06, 0x03],
+            ),
+            (
+                HubProperty::reset(AdvertisingName),
+                &[5, 0, 0x01, 0x01, 0x04],
+            ),
+            (
+                HubProperty::request_update(PrimaryMacAddress),
+                &[5, 0, 0x01, 0x0d, 0x05],
+            ),
+        ];
+        for (property, correct) in test_cases {
+            let msg = NotificationMessage::HubProperties(property.clone());
+            let serialised = msg.serialise();
+            assert_eq!(&serialised, correct);
+            assert_eq!(NotificationMessage::parse(&serialised).unwrap(), msg);
+        }
+
+        // Update from the hub carries the value
+        let msg = NotificationMessage::parse(&[6, 0, 0x01, 0x06, 0x06, 0x5a])
+            .unwrap();
+        assert_eq!(
+            msg,
+            NotificationMessage::HubProperties(HubProperty {
+                reference: BatteryVoltage,
+                operation: HubPropertyOperation::UpdateUpstream,
+                property: Some(HubPropertyValue::BatteryVoltage(90)),
+            })
+        );
+    }
+
+    #[test]
+    fn advertising_name() {
+        init();
+        assert_eq!(
+            HubPropertyValue::advertising_name("Technic 01").unwrap(),
+            HubPropertyValue::AdvertisingName(b"Technic 01".to_vec())
+        );
+        assert!(HubPropertyValue::advertising_name("14 byte name!!").is_ok());
+        assert!(HubPropertyValue::advertising_name("").is_err());
+        assert!(HubPropertyValue::advertising_name("fifteen bytes!!").is_err());
+        assert!(HubPropertyValue::advertising_name("Hub\n").is_err());
+        assert!(HubPropertyValue::advertising_name("Tëchnic").is_err());
+    }
+
+    #[test]
+    fn hub_alerts() {
+        init();
+        let test_cases: &[(HubAlert, &[u8])] = &[
+            (
+                HubAlert::enable_updates(AlertType::LowVoltage),
+                &[5, 0, 0x03, 0x01, 0x01],
+            ),
+            (
+                HubAlert::disable_updates(AlertType::HighCurrent),
+                &[5, 0, 0x03, 0x02, 0x02],
+            ),
+            (
+                HubAlert::request_update(AlertType::OverPowerCondition),
+                &[5, 0, 0x03, 0x04, 0x03],
+            ),
+            (
+                HubAlert {
+                    alert_type: AlertType::LowSignalStrength,
+                    operation: AlertOperation::Update,
+                    payload: Some(AlertPayload::StatusOk),
+                },
+                &[6, 0, 0x03, 0x03, 0x04, 0x00],
+            ),
+        ];
+        for (alert, correct) in test_cases {
+            let msg = NotificationMessage::HubAlerts(*alert);
+            let serialised = msg.serialise();
+            assert_eq!(&serialised, correct);
+            assert_eq!(NotificationMessage::parse(&serialised).unwrap(), msg);
+        }
+
+        // Updates must carry a valid status
+        assert!(NotificationMessage::parse(&[5, 0, 0x03, 0x01, 0x04]).is_err());
+        assert!(NotificationMessage::parse(&[6, 0, 0x03, 0x01, 0x04, 0x01])
+            .is_err());
+    }
+
+    #[test]
+    fn hub_actions() {
+        init();
+        use HubAction::*;
+
+        for (action, byte) in [
+            (SwitchOffHub, 0x01),
+            (Disconnect, 0x02),
+            (VccPortControlOn, 0x03),
+            (VccPortControlOff, 0x04),
+            (ActivateBusyIndication, 0x05),
+            (ResetBusyIndication, 0x06),
+        ] {
+            let msg = NotificationMessage::HubActions(action);
+            assert_eq!(msg.serialise(), [4, 0, 0x02, byte]);
+            assert!(!action.is_upstream());
+        }
+
+        for byte in [0x30, 0x31, 0x32] {
+            let msg = NotificationMessage::parse(&[4, 0, 0x02, byte]).unwrap();
+            let NotificationMessage::HubActions(action) = msg else {
+                panic!("wrong type");
+            };
+            assert!(action.is_upstream());
+        }
+    }
+
+    #[test]
+    fn network_commands() {
+        init();
+        use NetworkCommand::*;
+
+        let test_cases: &[(NetworkCommand, &[u8])] = &[
+            (ConnectionRequest(ConnectionButton::Pressed), &[0x02, 0x01]),
+            (ConnectionRequest(ConnectionButton::Released), &[0x02, 0x00]),
+            (FamilyRequest, &[0x03]),
+            (FamilySet(NetworkFamily::Red), &[0x04, 0x03]),
+            (JoinDenied, &[0x05]),
+            (GetFamily, &[0x06]),
+            (Family(NetworkFamily::White), &[0x07, 0x00]),
+            (GetSubfamily, &[0x08]),
+            (Subfamily(NetworkSubFamily::TwoFlashes), &[0x09, 0x02]),
+            (SubfamilySet(NetworkSubFamily::SevenFlashes), &[0x0a, 0x07]),
+            (GetExtendedFamily, &[0x0b]),
+            (
+                ExtendedFamily {
+                    family: NetworkFamily::Pink,
+                    subfamily: NetworkSubFamily::FourFlashes,
+                },
+                &[0x0c, 0x48],
+            ),
+            (
+                ExtendedFamilySet {
+                    family: NetworkFamily::Blue,
+                    subfamily: NetworkSubFamily::OneFlash,
+                },
+                &[0x0d, 0x14],
+            ),
+            (ResetLongPressTiming, &[0x0e]),
+        ];
+        for (command, correct) in test_cases {
+            let msg = NotificationMessage::HwNetworkCommands(*command);
+            let serialised = msg.serialise();
+            assert_eq!(&serialised[..3], &[serialised.len() as u8, 0, 0x08]);
+            assert_eq!(&serialised[3..], *correct);
+            assert_eq!(NotificationMessage::parse(&serialised).unwrap(), msg);
+        }
+
+        // Button states other than pressed and released are invalid
+        assert!(NotificationMessage::parse(&[5, 0, 0x08, 0x02, 0x02]).is_err());
+    }
+
+    #[test]
+    fn serialise_output_subcommands() {
+        init();
+        use PortOutputSubcommand::*;
+        use WriteDirectModeDataPayload::*;
+
+        // (subcommand, serialised, whether it parses back to itself)
+        let test_cases: &[(PortOutputSubcommand, &[u8], bool)] = &[
+            (
+                StartPower2 {
+                    power1: Power::Cw(50),
+                    power2: Power::Ccw(50),
+                },
+                &[0x02, 50, 0xce],
+                true,
+            ),
+            (
+                SetAccTime {
+                    time: 1000,
+                    profile_number: 0,
+                },
+                &[0x05, 0xe8, 0x03, 0x00],
+                true,
+            ),
+            (
+                SetDecTime {
+                    time: 500,
+                    profile_number: 1,
+                },
+                &[0x06, 0xf4, 0x01, 0x01],
+                true,
+            ),
+            (
+                StartSpeed {
+                    speed: -20,
+                    max_power: Power::Cw(100),
+                    use_acc_profile: true,
+                    use_dec_profile: false,
+                },
+                &[0x07, 0xec, 100, 0x01],
+                true,
+            ),
+            (
+                StartSpeed2 {
+                    speed1: 20,
+                    speed2: -20,
+                    max_power: 100,
+                    use_acc_profile: false,
+                    use_dec_profile: true,
+                },
+                &[0x08, 20, 0xec, 100, 0x02],
+                true,
+            ),
+            (
+                StartSpeedForTime {
+                    time: 2000,
+                    speed: 50,
+                    max_power: 100,
+                    end_state: EndState::Brake,
+                    use_acc_profile: true,
+                    use_dec_profile: true,
+                },
+                &[0x09, 0xd0, 0x07, 50, 100, 127, 0x03],
+                true,
+            ),
+            (
+                StartSpeedForTime2 {
+                    time: 2000,
+                    speed_l: 50,
+                    speed_r: -50,
+                    max_power: 100,
+                    end_state: EndState::Float,
+                    use_acc_profile: false,
+                    use_dec_profile: false,
+                },
+                &[0x0a, 0xd0, 0x07, 50, 0xce, 100, 0, 0x00],
+                true,
+            ),
+            (
+                StartSpeedForDegrees {
+                    degrees: 720,
+                    speed: 50,
+                    max_power: 100,
+                    end_state: EndState::Hold,
+                    use_acc_profile: true,
+                    use_dec_profile: true,
+                },
+                &[0x0b, 0xd0, 0x02, 0, 0, 50, 100, 126, 0x03],
+                true,
+            ),
+            (
+                StartSpeedForDegrees2 {
+                    degrees: 360,
+                    speed_l: 50,
+                    speed_r: 50,
+                    max_power: 100,
+                    end_state: EndState::Hold,
+                    use_acc_profile: true,
+                    use_dec_profile: true,
+                },
+                &[0x0c, 0x68, 0x01, 0, 0, 50, 50, 100, 126, 0x03],
+                true,
+            ),
+            (
+                GotoAbsolutePosition {
+                    abs_pos: -90,
+                    speed: 30,
+                    max_power: 100,
+                    end_state: EndState::Hold,
+                    use_acc_profile: false,
+                    use_dec_profile: false,
+                },
+                &[0x0d, 0xa6, 0xff, 0xff, 0xff, 30, 100, 126, 0x00],
+                true,
+            ),
+            (
+                GotoAbsolutePosition2 {
+                    abs_pos1: 90,
+                    abs_pos2: -90,
+                    speed: 30,
+                    max_power: 100,
+                    end_state: EndState::Brake,
+                    use_acc_profile: true,
+                    use_dec_profile: true,
+                },
+                &[
+                    0x0e, 90, 0, 0, 0, 0xa6, 0xff, 0xff, 0xff, 30, 100, 127,
+                    0x03,
+                ],
+                true,
+            ),
+            (
+                PresetEncoder2 {
+                    left_position: 1,
+                    right_position: -1,
+                },
+                &[0x14, 1, 0, 0, 0, 0xff, 0xff, 0xff, 0xff],
+                true,
+            ),
+            (
+                WriteDirect(WriteDirectPayload::HardwareReset),
+                &[0x50, 0xd4],
+                true,
+            ),
+            (
+                WriteDirect(WriteDirectPayload::TiltFactoryCalibration {
+                    orientation: CalibrationOrientation::Standing,
+                    pass_code: "Calib-Sensor".to_string(),
+                }),
+                &[
+                    0x50, 0x07, 0x02, b'C', b'a', b'l', b'i', b'b', b'-', b'S',
+                    b'e', b'n', b's', b'o', b'r',
+                ],
+                true,
+            ),
+            (
+                WriteDirectModeData(StartPower(Power::Brake)),
+                &[0x51, 0x00, 127],
+                true,
+            ),
+            (
+                WriteDirectModeData(PresetEncoder(0x1234)),
+                &[0x51, 0x02, 0x34, 0x12, 0, 0],
+                true,
+            ),
+            (
+                WriteDirectModeData(TiltImpactPreset(0)),
+                &[0x51, 0x03, 0, 0, 0, 0],
+                true,
+            ),
+            (
+                WriteDirectModeData(TiltConfigOrientation(Orientation::Top)),
+                &[0x51, 0x05, 5],
+                true,
+            ),
+            (
+                WriteDirectModeData(TiltConfigImpact {
+                    impact_threshold: 10,
+                    bump_holdoff: 20,
+                }),
+                &[0x51, 0x06, 10, 20],
+                true,
+            ),
+            // Shares mode 0x00 with StartPower so can't be told apart
+            (
+                WriteDirectModeData(SetRgbColorNo(9)),
+                &[0x51, 0x00, 9],
+                false,
+            ),
+        ];
+
+        for (subcommand, correct, round_trip) in test_cases {
+            assert_eq!(&subcommand.serialise(), correct);
+
+            let msg = NotificationMessage::PortOutputCommand(
+                PortOutputCommandFormat {
+                    port_id: 1,
+                    startup_info: StartupInfo::ExecuteImmediately,
+                    completion_info: CompletionInfo::NoAction,
+                    subcommand: subcommand.clone(),
+                },
+            );
+            let serialised = msg.serialise();
+            assert_eq!(serialised[0] as usize, serialised.len());
+            assert_eq!(&serialised[5..], *correct);
+            if *round_trip {
+                assert_eq!(
+                    NotificationMessage::parse(&serialised).unwrap(),
+                    msg
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn startup_and_completion_info() {
+        init();
+        let test_cases = [
+            (
+                StartupInfo::BufferIfNecessary,
+                CompletionInfo::NoAction,
+                0x00,
+            ),
+            (
+                StartupInfo::BufferIfNecessary,
+                CompletionInfo::CommandFeedback,
+                0x01,
+            ),
+            (
+                StartupInfo::ExecuteImmediately,
+                CompletionInfo::NoAction,
+                0x10,
+            ),
+            (
+                StartupInfo::ExecuteImmediately,
+                CompletionInfo::CommandFeedback,
+                0x11,
+            ),
+        ];
+
+        for (startup_info, completion_info, correct) in test_cases {
+            let msg = NotificationMessage::PortOutputCommand(
+                PortOutputCommandFormat {
+                    port_id: 1,
+                    startup_info,
+                    completion_info,
+                    subcommand: PortOutputSubcommand::GotoAbsolutePosition {
+                        abs_pos: 90,
+                        speed: 50,
+                        max_power: 100,
+                        end_state: EndState::Hold,
+                        use_acc_profile: true,
+                        use_dec_profile: true,
+                    },
+                },
+            );
+            let serialised = msg.serialise();
+            assert_eq!(serialised[4], correct);
+            assert_eq!(NotificationMessage::parse(&serialised).unwrap(), msg);
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq, Parse, Serialise)]
+    #[lpu(message_type = "PortValueSingle")]
+    struct EveryField {
+        a: u8,
+        b: i8,
+        c: u16,
+        d: i16,
+        e: u32,
+        f: i32,
+        g: f32,
+        h: bool,
+        i: [u8; 3],
+        j: DatasetType,
+        k: Vec<u8>,
+    }
+
+    #[test]
+    fn derive_struct_fields() {
+        init();
+        let value = EveryField {
+            a: 1,
+            b: -2,
+            c: 0x0304,
+            d: -5,
+            e: 0x06070809,
+            f: -10,
+            g: 1.5,
+            h: true,
+            i: [11, 12, 13],
+            j: DatasetType::Bits16,
+            k: vec![14, 15],
+        };
+        let ser = value.serialise();
+        assert_eq!(
+            ser,
+            [
+                0, 0, 0x45, 1, 0xfe, 0x04, 0x03, 0xfb, 0xff, 0x09, 0x08, 0x07,
+                0x06, 0xf6, 0xff, 0xff, 0xff, 0x00, 0x00, 0xc0, 0x3f, 1, 11,
+                12, 13, 1, 14, 15
+            ]
+        );
+        assert_eq!(EveryField::parse(ser[3..].iter()).unwrap(), value);
+
+        // Short input and an invalid bool are errors
+        assert!(EveryField::parse(ser[3..10].iter()).is_err());
+        let mut bad_bool = ser[3..].to_vec();
+        bad_bool[18] = 2;
+        assert!(EveryField::parse(bad_bool.iter()).is_err());
+    }
+
+    #[test]
+    fn frame_decoder() {
+        init();
+        let frames: &[&[u8]] = &[
+            &[5, 0, 0x82, 0x01, 0x0a],
+            &[15, 0, 4, 0, 1, 47, 0, 0, 16, 0, 0, 0, 16, 0, 0],
+            &[6, 0, 1, 2, 6, 0x1c],
+        ];
+        let long =
+            NotificationMessage::PortValueSingle(PortValueSingleFormat {
+                payload: vec![0x55; 200],
+            });
+        let long = long.serialise();
+        let mut stream: Vec<u8> = frames.concat();
+        stream.extend_from_slice(&long);
+        let expected: Vec<NotificationMessage> = frames
+            .iter()
+            .copied()
+            .chain([long.as_slice()])
+            .map(|f| NotificationMessage::parse(f).unwrap())
+            .collect();
+
+        // Every chunk size from one byte at a time to the whole stream at
+        // once
+        for chunk_size in 1..=stream.len() {
+            let mut decoder = FrameDecoder::new();
+            let decoded: Vec<NotificationMessage> = stream
+                .chunks(chunk_size)
+                .flat_map(|chunk| decoder.decode(chunk))
+                .map(|m| m.unwrap())
+                .collect();
+            assert_eq!(decoded, expected);
+            assert_eq!(decoder.buffered(), 0);
+        }
+
+        // Bad frames are reported and decoding picks up after them
+        let mut decoder = FrameDecoder::new();
+        let msgs = decoder.decode(&[0, 1, 4, 0, 0xff, 0x00, 5, 0, 0x82, 1, 10]);
+        assert_eq!(msgs.len(), 2);
+        assert!(msgs[0].is_err());
+        assert_eq!(
+            msgs[1].as_ref().unwrap(),
+            &NotificationMessage::parse(frames[0]).unwrap()
+        );
+
+        // Partial frames stay buffered
+        assert!(decoder.decode(&[0x81, 0x01, 0]).is_empty());
+        assert_eq!(decoder.buffered(), 3);
+    }
+
+    /// Xorshift generator, so the fuzz corpus is the same on every run
+    struct Corpus(u32);
+
+    impl Corpus {
+        fn byte(&mut self) -> u8 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            (self.0 >> 24) as u8
+        }
+
+        /// Frame of the given message type with a valid length header, so
+        /// that parsing gets past the header checks
+        fn frame(&mut self, message_type: u8, payload_len: usize) -> Vec<u8> {
+            let mut msg = vec![0, 0, message_type];
+            msg.extend((0..payload_len).map(|_| self.byte()));
+            msg[0] = msg.len() as u8;
+            msg
+        }
+    }
+
+    #[test]
+    fn parse_arbitrary_input() {
+        let mut corpus = Corpus(0x2545_f491);
+
+        assert!(NotificationMessage::parse(&[]).is_err());
+
+        // Every message type with every first payload byte, which selects
+        // the subcommand, property or port for most messages, followed by
+        // payloads that are short, exact and too long
+        for message_type in 0..=0xff {
+            for first in 0..=0xff {
+                for len in [0, 1, 3, 12] {
+                    let mut msg = corpus.frame(message_type, len + 1);
+                    msg[3] = first;
+                    let _ = NotificationMessage::parse(&msg);
+                }
+            }
+        }
+
+        // Random payloads for every message type
+        for message_type in 0..=0xff {
+            for _ in 0..200 {
+                let len = corpus.byte() as usize % 32;
+                let msg = corpus.frame(message_type, len);
+                let _ = NotificationMessage::parse(&msg);
+            }
+        }
+
+        // Entirely random bytes, length header included
+        for _ in 0..20_000 {
+            let len = corpus.byte() as usize % 40;
+            let msg: Vec<u8> = (0..len).map(|_| corpus.byte()).collect();
+            let _ = NotificationMessage::parse(&msg);
+        }
+    }
+
+    #[test]
+    fn port_value_single() {
+        init();
+        let mut registry = ValueFormatRegistry::new();
+
+        // Motor on port 0 reporting position (mode 2) as one i32, and
+        // colour sensor on port 1 reporting RGB (mode 5) as three u16
+        let msgs: &[&[u8]] = &[
+            &[10, 0, 0x47, 0, 2, 1, 0, 0, 0, 1],
+            &[10, 0, 0x44, 0, 2, 0x80, 1, 2, 4, 0],
+            &[10, 0, 0x47, 1, 5, 1, 0, 0, 0, 1],
+            &[10, 0, 0x44, 1, 5, 0x80, 3, 1, 5, 0],
+        ];
+        for msg in msgs {
+            registry.update(&NotificationMessage::parse(msg).unwrap());
+        }
+        assert_eq!(registry.input_mode(0), Some(2));
+        assert_eq!(
+            registry.current_value_format(1),
+            Some(ValueFormatType {
+                number_of_datasets: 3,
+                dataset_type: DatasetType::Bits16,
+                total_figures: 5,
+                decimals: 0,
+            })
+        );
+
+        let msg = NotificationMessage::parse(&[
+            15, 0, 0x45, 0, 0x68, 0x01, 0, 0, 1, 0x10, 0, 0x20, 0, 0x30, 0,
+        ])
+        .unwrap();
+        let NotificationMessage::PortValueSingle(value) = msg else {
+            panic!("wrong type");
+        };
+        let ports = value.process(&registry).unwrap();
+        assert_eq!(ports[&0], vec![TypedValue::Bits32(360)]);
+        assert_eq!(
+            ports[&1],
+            vec![
+                TypedValue::Bits16(0x10),
+                TypedValue::Bits16(0x20),
+                TypedValue::Bits16(0x30)
+            ]
+        );
+
+        // Unknown port, and a value cut short
+        let unknown = PortValueSingleFormat {
+            payload: vec![3, 0x01],
+        };
+        assert!(unknown.process(&registry).is_err());
+        let short = PortValueSingleFormat {
+            payload: vec![0, 0x01, 0x02],
+        };
+        assert!(short.process(&registry).is_err());
+    }
+
+    #[test]
+    fn serialise_extended_length() {
+        init();
+
+        // Largest message with a one byte header
+        let msg = NotificationMessage::PortValueSingle(PortValueSingleFormat {
+            payload: vec![0x55; 124],
+        });
+        let ser = msg.serialise();
+        assert_eq!(ser.len(), 127);
+        assert_eq!(&ser[..3], &[127, 0, 0x45]);
+        assert_eq!(NotificationMessage::parse(&ser).unwrap(), msg);
+
+        // One more byte needs the extended header
+        let msg = NotificationMessage::PortValueSingle(PortValueSingleFormat {
+            payload: vec![0x55; 125],
+        });
+        let ser = msg.serialise();
+        assert_eq!(ser.len(), 129);
+        assert_eq!(&ser[..4], &[0x81, 0x01, 0, 0x45]);
+        assert_eq!(NotificationMessage::parse(&ser).unwrap(), msg);
+
+        let msg = NotificationMessage::PortValueSingle(PortValueSingleFormat {
+            payload: vec![0x55; 300],
+        });
+        let ser = msg.serialise();
+        assert_eq!(ser.len(), 304);
+        assert_eq!(&ser[..4], &[0xb0, 0x02, 0, 0x45]);
+        assert_eq!(NotificationMessage::parse(&ser).unwrap(), msg);
+
+        // Longest message the header can describe
+        let msg = NotificationMessage::PortValueSingle(PortValueSingleFormat {
+            payload: vec![0x55; NotificationMessage::MAX_LENGTH - 4],
+        });
+        let ser = msg.serialise();
+        assert_eq!(ser.len(), NotificationMessage::MAX_LENGTH);
+        assert_eq!(&ser[..2], &[0xff, 0xff]);
+    }
+
+    #[test]
+    fn serialise_too_long() {
+        let msg = NotificationMessage::PortValueSingle(PortValueSingleFormat {
+            payload: vec![0x55; NotificationMessage::MAX_LENGTH - 3],
+        });
+        assert!(matches!(
+            msg.try_serialise(),
+            Err(Error::ParseError(e)) if e.contains("too long to serialise")
+        ));
+    }
+
+    #[test]
+    fn port_value_combined() {
+        init();
+        let mut registry = ValueFormatRegistry::new();
+
+        // Motor on port 0 reporting speed (mode 1, i8), position (mode 2,
+        // i32) and absolute position (mode 3, i16) in one combination
+        let msgs: &[&[u8]] = &[
+            &[10, 0, 0x44, 0, 1, 0x80, 1, 0, 4, 0],
+            &[10, 0, 0x44, 0, 2, 0x80, 1, 2, 4, 0],
+            &[10, 0, 0x44, 0, 3, 0x80, 1, 1, 3, 0],
+            &[9, 0, 0x42, 0, 0x01, 0, 0x10, 0x20, 0x30],
+            &[7, 0, 0x48, 0, 0x80, 0x07, 0x00],
+        ];
+        for msg in msgs {
+            registry.update(&NotificationMessage::parse(msg).unwrap());
+        }
+        assert_eq!(
+            registry.current_combination(0),
+            Some(&[0x10, 0x20, 0x30][..])
+        );
+
+        // Only speed and absolute position present
+        let msg = NotificationMessage::parse(&[
+            9, 0, 0x46, 0, 0x05, 0x00, 0xf6, 0x34, 0x12,
+        ])
+        .unwrap();
+        let NotificationMessage::PortValueCombinedmode(value) = msg else {
+            panic!("wrong type");
+        };
+        assert_eq!(
+            value.process(&registry).unwrap(),
+            vec![
+                CombinedValue {
+                    mode: 1,
+                    dataset: 0,
+                    value: TypedValue::Bits8(0xf6),
+                },
+                CombinedValue {
+                    mode: 3,
+                    dataset: 0,
+                    value: TypedValue::Bits16(0x1234),
+                },
+            ]
+        );
+
+        // No combination set up for the port
+        let unknown = PortValueCombinedFormat {
+            port_id: 1,
+            data: vec![0x01, 0x00, 0x00],
+        };
+        assert!(unknown.process(&registry).is_err());
+    }
 }
@@ -0,0 +1,1018 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Simulated Technic Medium Hub, for trying things out and testing without
+//! any hardware.
+//!
+//! A `SimHub` describes the hub and what is plugged into it. Connecting to
+//! it starts a task that plays the hub's side of the protocol over a
+//! `SimTransport`: it answers property, alert and value format requests,
+//! reports the attached devices once notifications are enabled, runs
+//! motors with a little inertia, and reports their speed and position,
+//! one mode at a time or combined.
+
+use crate::consts::{
+    AlertPayload, HubPropertyOperation, HubPropertyReference, HubType,
+    MessageType,
+};
+use crate::error::{Error, Result};
+use crate::hubs::{NotificationStream, Port, PortMap};
+use crate::notifications::{
+    AlertOperation, AttachedIo, CompletionInfo, DatasetType, ErrorCode,
+    ErrorMessageFormat, FeedbackMessage, HubAction, HubAlert, HubBatteryType,
+    HubProperty, HubPropertyValue, InputSetupCombined,
+    InputSetupCombinedSubcommand, InputSetupSingle, IoAttachEvent, IoTypeId,
+    ModeInformationRequest, ModeInformationType, NotificationMessage,
+    PortInputFormatCombinedFormat, PortInputFormatSingleFormat,
+    PortModeInformationType, PortModeInformationValue,
+    PortOutputCommandFeedbackFormat, PortOutputCommandFormat,
+    PortOutputSubcommand, PortValueCombinedFormat, PortValueSingleFormat,
+    Power, ValueFormatType, VersionNumber, WriteDirectModeDataPayload,
+};
+use crate::transport::{Link, Transport};
+use async_trait::async_trait;
+use log::debug;
+use num_traits::FromPrimitive;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, error::TryRecvError};
+use tokio::task::JoinHandle;
+
+/// How often the simulation runs
+const TICK: Duration = Duration::from_millis(10);
+/// How quickly a motor changes speed, in percent of full speed per second
+const ACCELERATION: f32 = 500.0;
+/// How far a motor turns at full speed, in degrees per second
+const FULL_SPEED: f32 = 1000.0;
+
+const FW_VERSION: VersionNumber = VersionNumber {
+    major: 1,
+    minor: 1,
+    bugfix: 0,
+    build: 0,
+};
+const HW_VERSION: VersionNumber = VersionNumber {
+    major: 0,
+    minor: 5,
+    bugfix: 0,
+    build: 0,
+};
+
+/// The internal devices of a Technic Medium Hub, by port id
+const INTERNAL_DEVICES: [(u8, IoTypeId); 9] = [
+    (0x32, IoTypeId::RgbLight),
+    (0x3b, IoTypeId::Current),
+    (0x3c, IoTypeId::Voltage),
+    (0x3d, IoTypeId::TechnicMediumHubTemperatureSensor),
+    (0x60, IoTypeId::TechnicMediumHubTemperatureSensor),
+    (0x61, IoTypeId::TechnicMediumHubAccelerometer),
+    (0x62, IoTypeId::TechnicMediumHubGyroSensor),
+    (0x63, IoTypeId::TechnicMediumHubTiltSensor),
+    (0x64, IoTypeId::TechnicMediumHubGestSensor),
+];
+
+/// Description of a simulated hub and the devices attached to it
+#[derive(Clone, Debug)]
+pub struct SimHub {
+    name: String,
+    address: [u8; 6],
+    devices: BTreeMap<u8, IoTypeId>,
+}
+
+impl SimHub {
+    /// A Technic Medium Hub with its internal devices and nothing plugged
+    /// into ports A-D
+    pub fn technic_medium_hub(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            address: [0x90, 0x84, 0x2b, 0x00, 0x00, 0x01],
+            devices: INTERNAL_DEVICES.into_iter().collect(),
+        }
+    }
+
+    /// Plug a device into a port, replacing anything already there
+    ///
+    /// # Panics
+    ///
+    /// If the hub has no such port
+    pub fn with_device(mut self, port: Port, io_type_id: IoTypeId) -> Self {
+        let port_id = PortMap::for_hub_type(self.hub_type())
+            .id(port)
+            .unwrap_or_else(|| panic!("No port {:?} on the hub", port));
+        self.devices.insert(port_id, io_type_id);
+        self
+    }
+
+    pub fn with_address(mut self, address: [u8; 6]) -> Self {
+        self.address = address;
+        self
+    }
+
+    pub fn hub_type(&self) -> HubType {
+        HubType::TechnicMediumHub
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The hub's address in the usual colon separated form
+    pub fn address(&self) -> String {
+        self.address
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+
+    /// Switch the hub on and connect to it. Each connection simulates the
+    /// hub afresh, from the state described here.
+    ///
+    /// # Panics
+    ///
+    /// If called outside a Tokio runtime, since the hub runs as a task on
+    /// it
+    pub fn connect(&self) -> SimTransport {
+        let (downstream, commands) = mpsc::unbounded_channel();
+        let link = Arc::new(Link::new());
+        let simulation = Simulation::new(self, link.clone());
+        let task = tokio::spawn(simulation.run(commands));
+        SimTransport {
+            downstream,
+            link,
+            task,
+        }
+    }
+}
+
+/// What the transport asks of the simulated hub
+#[derive(Debug)]
+enum Downstream {
+    Frame(Vec<u8>),
+    Subscribe,
+}
+
+/// Connection to a simulated hub
+#[derive(Debug)]
+pub struct SimTransport {
+    downstream: mpsc::UnboundedSender<Downstream>,
+    link: Arc<Link>,
+    task: JoinHandle<()>,
+}
+
+impl SimTransport {
+    fn downstream(&self, msg: Downstream) -> Result<()> {
+        if !self.link.is_connected() {
+            return Err(Error::HubError(
+                "Simulated hub is disconnected".to_string(),
+            ));
+        }
+        self.downstream.send(msg).map_err(|_| {
+            Error::HubError("Simulated hub has stopped".to_string())
+        })
+    }
+}
+
+#[async_trait]
+impl Transport for SimTransport {
+    async fn send(&self, frame: &[u8]) -> Result<()> {
+        self.downstream(Downstream::Frame(frame.to_vec()))
+    }
+
+    async fn notifications(&self) -> Result<NotificationStream<Vec<u8>>> {
+        self.link.notifications()
+    }
+
+    async fn subscribe(&self) -> Result<()> {
+        self.downstream(Downstream::Subscribe)
+    }
+
+    async fn is_connected(&self) -> Result<bool> {
+        Ok(self.link.is_connected())
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        self.task.abort();
+        self.link.close();
+        Ok(())
+    }
+}
+
+impl Drop for SimTransport {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Input mode a device reports values in
+#[derive(Debug, Default)]
+struct InputMode {
+    mode: u8,
+    delta: u32,
+    notification_enabled: bool,
+    /// Last value reported, to apply the delta to
+    reported: Option<i32>,
+}
+
+/// Modes a device reports values in together
+#[derive(Debug, Default)]
+struct CombinedMode {
+    /// Locked for setup, between the lock and unlock commands
+    locked: bool,
+    combination_index: u8,
+    /// Mode in the high nibble and dataset in the low nibble of each entry
+    mode_dataset: Vec<u8>,
+    enabled: bool,
+    /// Last values reported
+    reported: Option<Vec<i32>>,
+}
+
+#[derive(Debug)]
+struct SimDevice {
+    io_type_id: IoTypeId,
+    input: InputMode,
+    combined: CombinedMode,
+    /// Speed or power the device has been asked for, in percent
+    target: f32,
+    /// Current speed, in percent
+    speed: f32,
+    /// Position, in degrees
+    position: f32,
+}
+
+impl SimDevice {
+    fn new(io_type_id: IoTypeId) -> Self {
+        Self {
+            io_type_id,
+            input: Default::default(),
+            combined: Default::default(),
+            target: 0.0,
+            speed: 0.0,
+            position: 0.0,
+        }
+    }
+
+    fn is_motor(&self) -> bool {
+        use IoTypeId::*;
+        matches!(
+            self.io_type_id,
+            Motor
+                | SystemTrainMotor
+                | ExternalMotor
+                | InternalMotor
+                | DuploTrainBaseMotor
+                | TechnicLargeLinearMotor
+                | TechnicXlargeLinearMotor
+                | TechnicMediumAngularMotor
+                | TechnicLargeAngularMotor
+                | TechnicMediumAngularMotorGrey
+                | TechnicLargeAngularMotorGrey
+        )
+    }
+
+    /// Whether the motor has an encoder, and so speed and position modes
+    fn is_tacho_motor(&self) -> bool {
+        self.is_motor()
+            && !matches!(
+                self.io_type_id,
+                IoTypeId::Motor
+                    | IoTypeId::SystemTrainMotor
+                    | IoTypeId::DuploTrainBaseMotor
+            )
+    }
+
+    /// Move the speed towards the target and the position along with it
+    fn update(&mut self, dt: f32) {
+        if !self.is_motor() {
+            return;
+        }
+        let step = ACCELERATION * dt;
+        self.speed += (self.target - self.speed).clamp(-step, step);
+        self.position += self.speed / 100.0 * FULL_SPEED * dt;
+    }
+
+    /// Value format of the modes that report values, see `value`
+    fn value_format(&self, mode: u8) -> Option<ValueFormatType> {
+        if !self.is_tacho_motor() {
+            return None;
+        }
+        let (dataset_type, total_figures) = match mode {
+            1 => (DatasetType::Bits8, 4),
+            2 => (DatasetType::Bits32, 4),
+            3 => (DatasetType::Bits16, 3),
+            _ => return None,
+        };
+        Some(ValueFormatType {
+            number_of_datasets: 1,
+            dataset_type,
+            total_figures,
+            decimals: 0,
+        })
+    }
+
+    /// Value in an input mode and its wire encoding. Tacho motors report
+    /// speed in mode 1, position in mode 2 and absolute position in mode 3;
+    /// nothing else reports values.
+    fn value(&self, mode: u8) -> Option<(i32, Vec<u8>)> {
+        if !self.is_tacho_motor() {
+            return None;
+        }
+        Some(match mode {
+            1 => {
+                let speed = self.speed.round() as i8;
+                (speed as i32, speed.to_le_bytes().to_vec())
+            }
+            2 => {
+                let position = self.position.round() as i32;
+                (position, position.to_le_bytes().to_vec())
+            }
+            3 => {
+                let position = self.position.round() as i32;
+                let absolute = ((position + 180).rem_euclid(360) - 180) as i16;
+                (absolute as i32, absolute.to_le_bytes().to_vec())
+            }
+            _ => return None,
+        })
+    }
+}
+
+/// The hub's side of the connection
+struct Simulation {
+    name: String,
+    address: [u8; 6],
+    devices: BTreeMap<u8, SimDevice>,
+    link: Arc<Link>,
+}
+
+impl Simulation {
+    fn new(hub: &SimHub, link: Arc<Link>) -> Self {
+        Self {
+            name: hub.name.clone(),
+            address: hub.address,
+            devices: hub
+                .devices
+                .iter()
+                .map(|(port, io_type_id)| (*port, SimDevice::new(*io_type_id)))
+                .collect(),
+            link,
+        }
+    }
+
+    async fn run(mut self, mut commands: mpsc::UnboundedReceiver<Downstream>) {
+        let mut ticks = tokio::time::interval(TICK);
+        let mut last = Instant::now();
+        loop {
+            ticks.tick().await;
+            loop {
+                match commands.try_recv() {
+                    Ok(Downstream::Subscribe) => self.report_attached(),
+                    Ok(Downstream::Frame(frame)) => {
+                        if !self.handle(&frame) {
+                            self.link.close();
+                            return;
+                        }
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            }
+
+            let now = Instant::now();
+            let dt = (now - last).as_secs_f32();
+            last = now;
+            let mut reports = Vec::new();
+            for (port_id, device) in self.devices.iter_mut() {
+                device.update(dt);
+                if let Some(frame) = Self::value_report(*port_id, device) {
+                    reports.push(frame);
+                }
+                if let Some(frame) = Self::combined_report(*port_id, device) {
+                    reports.push(frame);
+                }
+            }
+            for frame in reports {
+                self.link.send(frame);
+            }
+        }
+    }
+
+    fn send(&self, msg: NotificationMessage) {
+        self.link.send(msg.serialise());
+    }
+
+    fn report_attached(&self) {
+        for (port, device) in &self.devices {
+            self.send(NotificationMessage::HubAttachedIo(AttachedIo {
+                port: *port,
+                event: IoAttachEvent::AttachedIo {
+                    io_type_id: device.io_type_id,
+                    hw_rev: HW_VERSION,
+                    fw_rev: FW_VERSION,
+                },
+            }));
+        }
+    }
+
+    fn error(&self, command_type: MessageType, error_code: ErrorCode) {
+        self.send(NotificationMessage::GenericErrorMessages(
+            ErrorMessageFormat {
+                command_type,
+                error_code,
+            },
+        ));
+    }
+
+    /// Act on a frame from the host. Returns false once the hub has gone
+    /// away.
+    fn handle(&mut self, frame: &[u8]) -> bool {
+        let msg = match NotificationMessage::parse(frame) {
+            Ok(msg) => msg,
+            Err(e) => {
+                debug!("Simulated hub ignoring {:02x?}: {}", frame, e);
+                return true;
+            }
+        };
+        match msg {
+            NotificationMessage::HubProperties(property) => {
+                self.hub_property(property)
+            }
+            NotificationMessage::HubActions(action) => {
+                return self.hub_action(action)
+            }
+            NotificationMessage::HubAlerts(alert) => self.hub_alert(alert),
+            NotificationMessage::PortModeInformationRequest(req) => {
+                self.mode_information(req)
+            }
+            NotificationMessage::PortInputFormatSetupSingle(setup) => {
+                self.input_setup(setup)
+            }
+            NotificationMessage::PortInputFormatSetupCombinedmode(setup) => {
+                self.combined_setup(setup)
+            }
+            NotificationMessage::PortOutputCommand(cmd) => {
+                self.output_command(cmd)
+            }
+            msg => {
+                if let Some(command_type) =
+                    MessageType::from_u8(msg.message_type())
+                {
+                    self.error(command_type, ErrorCode::CommandNotRecognized);
+                }
+            }
+        }
+        true
+    }
+
+    fn property_value(
+        &self,
+        reference: HubPropertyReference,
+    ) -> Option<HubPropertyValue> {
+        use HubPropertyReference::*;
+        let version = |v: VersionNumber| {
+            let bytes = v.serialise();
+            i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+        };
+        Some(match reference {
+            AdvertisingName => {
+                HubPropertyValue::AdvertisingName(self.name.as_bytes().to_vec())
+            }
+            Button => HubPropertyValue::Button(0),
+            FwVersion => HubPropertyValue::FwVersion(version(FW_VERSION)),
+            HwVersion => HubPropertyValue::HwVersion(version(HW_VERSION)),
+            Rssi => HubPropertyValue::Rssi(-50),
+            BatteryVoltage => HubPropertyValue::BatteryVoltage(100),
+            BatteryType => {
+                HubPropertyValue::BatteryType(HubBatteryType::Normal)
+            }
+            ManufacturerName => {
+                HubPropertyValue::ManufacturerName(b"LEGO System A/S".to_vec())
+            }
+            RadioFirmwareVersion => {
+                HubPropertyValue::RadioFirmwareVersion(b"2_02_01".to_vec())
+            }
+            LegoWirelessProtocolVersion => {
+                HubPropertyValue::LegoWirelessProtocolVersion(0x0300)
+            }
+            SystemTypeId => HubPropertyValue::SystemTypeId(0x80),
+            HwNetworkId => HubPropertyValue::HwNetworkId(0),
+            PrimaryMacAddress => {
+                HubPropertyValue::PrimaryMacAddress(self.address)
+            }
+            _ => return None,
+        })
+    }
+
+    fn hub_property(&mut self, property: HubProperty) {
+        match property.operation {
+            HubPropertyOperation::SetDownstream => match property.property {
+                Some(HubPropertyValue::AdvertisingName(name)) => {
+                    self.name = String::from_utf8_lossy(&name).into_owned();
+                }
+                // Only the name and network id can be set
+                Some(HubPropertyValue::HwNetworkId(_)) => {}
+                _ => self
+                    .error(MessageType::HubProperties, ErrorCode::InvalidUse),
+            },
+            HubPropertyOperation::EnableUpdatesDownstream
+            | HubPropertyOperation::RequestUpdateDownstream => {
+                match self.property_value(property.reference) {
+                    Some(value) => self.send(
+                        NotificationMessage::HubProperties(HubProperty {
+                            reference: property.reference,
+                            operation: HubPropertyOperation::UpdateUpstream,
+                            property: Some(value),
+                        }),
+                    ),
+                    None => self.error(
+                        MessageType::HubProperties,
+                        ErrorCode::InvalidUse,
+                    ),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn hub_action(&mut self, action: HubAction) -> bool {
+        let warning = match action {
+            HubAction::SwitchOffHub => HubAction::HubWillSwitchOff,
+            HubAction::Disconnect => HubAction::HubWillDisconnect,
+            _ => return true,
+        };
+        self.send(NotificationMessage::HubActions(warning));
+        false
+    }
+
+    fn hub_alert(&mut self, alert: HubAlert) {
+        match alert.operation {
+            AlertOperation::EnableUpdates | AlertOperation::RequestUpdates => {
+                self.send(NotificationMessage::HubAlerts(HubAlert {
+                    alert_type: alert.alert_type,
+                    operation: AlertOperation::Update,
+                    payload: Some(AlertPayload::StatusOk),
+                }))
+            }
+            _ => {}
+        }
+    }
+
+    /// Only value formats are known, and only for modes that report values
+    fn mode_information(&mut self, req: ModeInformationRequest) {
+        let format = match req.information_type {
+            ModeInformationType::ValueFormat => self
+                .devices
+                .get(&req.port_id)
+                .and_then(|device| device.value_format(req.mode)),
+            _ => None,
+        };
+        match format {
+            Some(format) => {
+                self.send(NotificationMessage::PortModeInformation(
+                    PortModeInformationValue {
+                        port_id: req.port_id,
+                        mode: req.mode,
+                        information_type: PortModeInformationType::ValueFormat(
+                            format,
+                        ),
+                    },
+                ))
+            }
+            None => self.error(
+                MessageType::PortModeInformationRequest,
+                ErrorCode::InvalidUse,
+            ),
+        }
+    }
+
+    fn input_setup(&mut self, setup: InputSetupSingle) {
+        let Some(device) = self.devices.get_mut(&setup.port_id) else {
+            self.error(
+                MessageType::PortInputFormatSetupSingle,
+                ErrorCode::InvalidUse,
+            );
+            return;
+        };
+        // Modes set up for a combination are only acknowledged
+        if !device.combined.locked {
+            device.input = InputMode {
+                mode: setup.mode,
+                delta: setup.delta,
+                notification_enabled: setup.notification_enabled,
+                reported: None,
+            };
+        }
+        self.send(NotificationMessage::PortInputFormatSingle(
+            PortInputFormatSingleFormat {
+                port_id: setup.port_id,
+                mode: setup.mode,
+                delta: setup.delta,
+                notification_enabled: setup.notification_enabled,
+            },
+        ));
+    }
+
+    /// Lock, set the combination, then unlock to start reporting it
+    fn combined_setup(&mut self, setup: InputSetupCombined) {
+        let port_id = setup.port_id;
+        let Some(device) = self.devices.get_mut(&port_id) else {
+            self.error(
+                MessageType::PortInputFormatSetupCombinedmode,
+                ErrorCode::InvalidUse,
+            );
+            return;
+        };
+        // Every mode reports a single dataset, and the value pointer has a
+        // bit for each of at most 16 entries
+        let reported = match &setup.subcommand {
+            InputSetupCombinedSubcommand::SetModeanddatasetCombinations {
+                mode_dataset,
+                ..
+            } => {
+                mode_dataset.len() <= 16
+                    && mode_dataset.iter().all(|entry| {
+                        entry & 0x0f == 0
+                            && device.value_format(entry >> 4).is_some()
+                    })
+            }
+            _ => true,
+        };
+        let combined = &mut device.combined;
+        match setup.subcommand {
+            InputSetupCombinedSubcommand::LockLpf2DeviceForSetup => {
+                *combined = CombinedMode {
+                    locked: true,
+                    ..Default::default()
+                };
+            }
+            InputSetupCombinedSubcommand::SetModeanddatasetCombinations {
+                combination_index,
+                mode_dataset,
+            } if combined.locked && reported => {
+                combined.combination_index = combination_index;
+                combined.mode_dataset = mode_dataset;
+            }
+            InputSetupCombinedSubcommand::UnlockAndStartMultiEnabled
+            | InputSetupCombinedSubcommand::UnlockAndStartMultiDisabled
+                if combined.locked =>
+            {
+                combined.locked = false;
+                combined.enabled = setup.subcommand
+                    == InputSetupCombinedSubcommand::UnlockAndStartMultiEnabled
+                    && !combined.mode_dataset.is_empty();
+                let pointer = (1u32 << combined.mode_dataset.len()) - 1;
+                let ack = PortInputFormatCombinedFormat::new(
+                    port_id,
+                    combined.combination_index,
+                    combined.enabled,
+                    pointer as u16,
+                );
+                self.send(NotificationMessage::PortInputFormatCombinedmode(
+                    ack,
+                ));
+            }
+            _ => self.error(
+                MessageType::PortInputFormatSetupCombinedmode,
+                ErrorCode::InvalidUse,
+            ),
+        }
+    }
+
+    fn output_command(&mut self, cmd: PortOutputCommandFormat) {
+        let Some(device) = self.devices.get_mut(&cmd.port_id) else {
+            self.error(MessageType::PortOutputCommand, ErrorCode::InvalidUse);
+            return;
+        };
+        let accepted = match cmd.subcommand {
+            PortOutputSubcommand::StartSpeed { speed, .. }
+                if device.is_tacho_motor() =>
+            {
+                device.target = (speed as f32).clamp(-100.0, 100.0);
+                true
+            }
+            PortOutputSubcommand::WriteDirectModeData(
+                WriteDirectModeDataPayload::StartPower(power),
+            ) if device.is_motor() => {
+                device.target = match power {
+                    Power::Cw(p) => p.min(100) as f32,
+                    Power::Ccw(p) => -(p.min(100) as f32),
+                    Power::Float | Power::Brake => 0.0,
+                };
+                true
+            }
+            PortOutputSubcommand::WriteDirectModeData(
+                WriteDirectModeDataPayload::StartPower(_),
+            ) => device.io_type_id == IoTypeId::LedLight,
+            PortOutputSubcommand::WriteDirectModeData(
+                WriteDirectModeDataPayload::SetRgbColors { .. },
+            ) => device.io_type_id == IoTypeId::RgbLight,
+            _ => false,
+        };
+        if cmd.completion_info == CompletionInfo::CommandFeedback {
+            // Buffer empty and command completed, or discarded, and idle
+            let feedback = FeedbackMessage {
+                port_id: cmd.port_id,
+                empty_cmd_in_progress: false,
+                empty_cmd_completed: accepted,
+                discarded: !accepted,
+                idle: true,
+                busy_full: false,
+            };
+            self.send(NotificationMessage::PortOutputCommandFeedback(
+                PortOutputCommandFeedbackFormat {
+                    msg1: feedback,
+                    msg2: None,
+                    msg3: None,
+                },
+            ));
+        }
+    }
+
+    /// A port value message if the device reports values and its value
+    /// has moved by at least the delta since it was last reported
+    fn value_report(port_id: u8, device: &mut SimDevice) -> Option<Vec<u8>> {
+        if !device.input.notification_enabled {
+            return None;
+        }
+        let (value, bytes) = device.value(device.input.mode)?;
+        let due = match device.input.reported {
+            None => true,
+            Some(reported) => {
+                let change = (value - reported).unsigned_abs();
+                change > 0 && change >= device.input.delta
+            }
+        };
+        if !due {
+            return None;
+        }
+        device.input.reported = Some(value);
+        let mut payload = vec![port_id];
+        payload.extend_from_slice(&bytes);
+        let msg = NotificationMessage::PortValueSingle(PortValueSingleFormat {
+            payload,
+        });
+        Some(msg.serialise())
+    }
+
+    /// A combined value message with every value in the combination, if
+    /// any of them has changed since they were last reported
+    fn combined_report(port_id: u8, device: &mut SimDevice) -> Option<Vec<u8>> {
+        if !device.combined.enabled {
+            return None;
+        }
+        let (values, bytes): (Vec<i32>, Vec<Vec<u8>>) = device
+            .combined
+            .mode_dataset
+            .iter()
+            .map(|entry| device.value(entry >> 4))
+            .collect::<Option<Vec<_>>>()?
+            .into_iter()
+            .unzip();
+        if device.combined.reported.as_ref() == Some(&values) {
+            return None;
+        }
+        device.combined.reported = Some(values);
+        let pointer = (1u32 << bytes.len()) - 1;
+        let mut data = (pointer as u16).to_le_bytes().to_vec();
+        data.extend(bytes.concat());
+        let msg = NotificationMessage::PortValueCombinedmode(
+            PortValueCombinedFormat { port_id, data },
+        );
+        Some(msg.serialise())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::consts::DeviceType;
+    use crate::hubs::{Hub, HubProperties, TechnicHub};
+
+    async fn connect(sim: &SimHub) -> TechnicHub {
+        let properties = HubProperties {
+            name: sim.name().to_string(),
+            mac_address: sim.address(),
+            ..Default::default()
+        };
+        TechnicHub::with_transport(
+            Arc::new(sim.connect()),
+            sim.hub_type(),
+            properties,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn properties_and_devices() {
+        let sim = SimHub::technic_medium_hub("Sim")
+            .with_device(Port::A, IoTypeId::TechnicLargeLinearMotor);
+        let hub = connect(&sim).await;
+
+        assert_eq!(hub.battery_voltage().await.unwrap(), 100);
+        assert_eq!(hub.fw_version().await.unwrap(), FW_VERSION);
+        assert_eq!(
+            hub.primary_mac_address().await.unwrap(),
+            [0x90, 0x84, 0x2b, 0x00, 0x00, 0x01]
+        );
+        hub.set_name("Renamed").await.unwrap();
+        assert_eq!(hub.name().await.unwrap(), "Renamed");
+
+        let io = hub
+            .wait_for_device(Port::A, DeviceType::TechnicLargeLinearMotor)
+            .await
+            .unwrap();
+        assert_eq!(io.port_id, 0);
+        let motor = hub.port(Port::A).await.unwrap();
+        assert_eq!(motor.io_type_id(), IoTypeId::TechnicLargeLinearMotor);
+        assert!(hub.port(Port::B).await.is_err());
+
+        // Commands the hub doesn't know are rejected
+        let err = hub.network_family().await.unwrap_err();
+        assert!(matches!(err, Error::Protocol { .. }));
+
+        hub.switch_off().await.unwrap();
+        tokio::time::sleep(TICK * 3).await;
+        assert!(!hub.is_connected().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn motor_inertia() {
+        let sim = SimHub::technic_medium_hub("Sim")
+            .with_device(Port::B, IoTypeId::TechnicLargeAngularMotor);
+        let hub = connect(&sim).await;
+        let mut motor = hub.port(Port::B).await.unwrap();
+
+        // Report the position on every degree
+        let mut values = hub
+            .notifications()
+            .message_type(MessageType::PortValueSingle)
+            .port(1);
+        let setup =
+            NotificationMessage::PortInputFormatSetupSingle(InputSetupSingle {
+                port_id: 1,
+                mode: 2,
+                delta: 1,
+                notification_enabled: true,
+            });
+        hub.send_raw(&setup.serialise()).await.unwrap();
+
+        motor.start_speed(100, Power::Cw(100)).await.unwrap();
+        let position =
+            |raw: &[u8]| i32::from_le_bytes([raw[4], raw[5], raw[6], raw[7]]);
+        let mut last = 0;
+        let mut steps = Vec::new();
+        while last < 500 {
+            let raw = values.recv().await.unwrap().raw;
+            let current = position(&raw);
+            assert!(current >= last);
+            steps.push(current - last);
+            last = current;
+        }
+        // Speeding up rather than starting at full speed
+        let early: i32 = steps.iter().skip(1).take(3).sum();
+        let late: i32 = steps.iter().rev().take(3).sum();
+        assert!(early < late, "{:?}", steps);
+    }
+
+    #[tokio::test]
+    async fn command_feedback() {
+        use crate::notifications::StartupInfo;
+
+        let sim = SimHub::technic_medium_hub("Sim")
+            .with_device(Port::A, IoTypeId::TechnicLargeLinearMotor);
+        let hub = connect(&sim).await;
+        let mut motor = hub.port(Port::A).await.unwrap();
+        let mut feedback = hub
+            .notifications()
+            .message_type(MessageType::PortOutputCommandFeedback);
+
+        // Motors take speed, and the hub LED isn't attached to port A
+        for (subcommand, status) in [
+            (
+                PortOutputSubcommand::StartSpeed {
+                    speed: 50,
+                    max_power: Power::Cw(100),
+                    use_acc_profile: false,
+                    use_dec_profile: false,
+                },
+                0x0a,
+            ),
+            (
+                PortOutputSubcommand::WriteDirectModeData(
+                    WriteDirectModeDataPayload::SetRgbColors {
+                        red: 1,
+                        green: 2,
+                        blue: 3,
+                    },
+                ),
+                0x0c,
+            ),
+        ] {
+            motor
+                .output_command(
+                    subcommand,
+                    StartupInfo::ExecuteImmediately,
+                    CompletionInfo::CommandFeedback,
+                )
+                .await
+                .unwrap();
+            let raw = feedback.recv().await.unwrap().raw;
+            assert_eq!(raw, [0x05, 0x00, 0x82, 0x00, status]);
+        }
+    }
+
+    #[tokio::test]
+    async fn combined_entries_limited() {
+        use crate::dispatcher::NotificationReceiver;
+
+        // The next error or combined mode reply
+        async fn reply(
+            replies: &mut NotificationReceiver,
+        ) -> NotificationMessage {
+            loop {
+                let message = replies.recv().await.unwrap().message.unwrap();
+                if matches!(
+                    message,
+                    NotificationMessage::GenericErrorMessages(_)
+                        | NotificationMessage::PortInputFormatCombinedmode(_)
+                ) {
+                    return message;
+                }
+            }
+        }
+
+        let sim = SimHub::technic_medium_hub("Sim")
+            .with_device(Port::A, IoTypeId::TechnicLargeLinearMotor);
+        let hub = connect(&sim).await;
+        let mut replies = hub.notifications();
+        let send = |subcommand| {
+            let msg = NotificationMessage::PortInputFormatSetupCombinedmode(
+                InputSetupCombined {
+                    port_id: 0,
+                    subcommand,
+                },
+            );
+            let hub = &hub;
+            async move { hub.send_raw(&msg.serialise()).await.unwrap() }
+        };
+        let combination = |entries| {
+            InputSetupCombinedSubcommand::SetModeanddatasetCombinations {
+                combination_index: 0,
+                mode_dataset: vec![0x10; entries],
+            }
+        };
+        let rejected =
+            NotificationMessage::GenericErrorMessages(ErrorMessageFormat {
+                command_type: MessageType::PortInputFormatSetupCombinedmode,
+                error_code: ErrorCode::InvalidUse,
+            });
+
+        send(InputSetupCombinedSubcommand::LockLpf2DeviceForSetup).await;
+        for entries in [17, 32] {
+            send(combination(entries)).await;
+            assert_eq!(
+                reply(&mut replies).await,
+                rejected,
+                "{} entries",
+                entries
+            );
+        }
+
+        send(combination(16)).await;
+        send(InputSetupCombinedSubcommand::UnlockAndStartMultiEnabled).await;
+        assert_eq!(
+            reply(&mut replies).await,
+            NotificationMessage::PortInputFormatCombinedmode(
+                PortInputFormatCombinedFormat::new(0, 0, true, 0xffff)
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn discovered_through_powered_up() {
+        use crate::{HubAddress, HubFilter, PoweredUp};
+
+        let sim = SimHub::technic_medium_hub("Sim")
+            .with_device(Port::C, IoTypeId::TechnicXlargeLinearMotor);
+        let mut pu = PoweredUp::without_adapter();
+        pu.add_simulated_hub(sim);
+
+        let discovered = pu
+            .wait_for_hub_filter(HubFilter::Name("Sim".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(discovered.hub_type, HubType::TechnicMediumHub);
+        assert_eq!(
+            discovered.addr,
+            HubAddress::Simulated("90:84:2B:00:00:01".to_string())
+        );
+
+        let hub = pu.create_hub(&discovered).await.unwrap();
+        let mut motor = hub.port(Port::C).await.unwrap();
+        motor.start_speed(50, Power::Cw(100)).await.unwrap();
+        hub.disconnect().await.unwrap();
+        assert!(motor.start_speed(0, Power::Cw(100)).await.is_err());
+    }
+}
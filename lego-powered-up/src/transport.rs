@@ -0,0 +1,146 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The link that carries LWP3 frames between us and a hub, so that hubs
+//! and devices don't depend on a particular Bluetooth back-end.
+
+use crate::error::{Error, Result};
+use crate::hubs::NotificationStream;
+use async_trait::async_trait;
+use btleplug::api::{Characteristic, Peripheral as _, WriteType};
+use btleplug::platform::Peripheral;
+use futures::stream::StreamExt;
+use log::warn;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Number of frames a notification stream of an in-process transport may
+/// fall behind
+const CHANNEL_CAPACITY: usize = 256;
+
+/// Trait describing a connection to a hub
+#[async_trait]
+pub trait Transport: Debug + Send + Sync {
+    /// Send one frame to the hub
+    async fn send(&self, frame: &[u8]) -> Result<()>;
+    /// Stream of the frames the hub sends from now on
+    async fn notifications(&self) -> Result<NotificationStream<Vec<u8>>>;
+    /// Ask the hub to start sending notifications. Streams opened before
+    /// this see everything the hub reports straight after.
+    async fn subscribe(&self) -> Result<()>;
+    async fn is_connected(&self) -> Result<bool>;
+    async fn disconnect(&self) -> Result<()>;
+}
+
+/// Transport over a btleplug peripheral and the hub's LWP3 characteristic
+#[derive(Debug, Clone)]
+pub struct BleTransport {
+    peripheral: Peripheral,
+    characteristic: Characteristic,
+}
+
+impl BleTransport {
+    pub fn new(peripheral: Peripheral, characteristic: Characteristic) -> Self {
+        Self {
+            peripheral,
+            characteristic,
+        }
+    }
+
+    pub fn peripheral(&self) -> &Peripheral {
+        &self.peripheral
+    }
+}
+
+#[async_trait]
+impl Transport for BleTransport {
+    async fn send(&self, frame: &[u8]) -> Result<()> {
+        Ok(self
+            .peripheral
+            .write(&self.characteristic, frame, WriteType::WithoutResponse)
+            .await?)
+    }
+
+    async fn notifications(&self) -> Result<NotificationStream<Vec<u8>>> {
+        let uuid = self.characteristic.uuid;
+        let notifications = self.peripheral.notifications().await?;
+        Ok(Box::pin(notifications.filter_map(move |data| {
+            futures::future::ready((data.uuid == uuid).then_some(data.value))
+        })))
+    }
+
+    async fn subscribe(&self) -> Result<()> {
+        Ok(self.peripheral.subscribe(&self.characteristic).await?)
+    }
+
+    async fn is_connected(&self) -> Result<bool> {
+        Ok(self.peripheral.is_connected().await?)
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        Ok(self.peripheral.disconnect().await?)
+    }
+}
+
+/// The hub's side of an in-process transport, such as a simulated or
+/// replayed hub, which hands frames to every open notification stream
+#[derive(Debug)]
+pub(crate) struct Link {
+    /// Dropped when the connection closes, which ends the notification
+    /// streams
+    upstream: Mutex<Option<broadcast::Sender<Vec<u8>>>>,
+    connected: AtomicBool,
+}
+
+impl Link {
+    pub(crate) fn new() -> Self {
+        let (upstream, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self {
+            upstream: Mutex::new(Some(upstream)),
+            connected: AtomicBool::new(true),
+        }
+    }
+
+    pub(crate) fn send(&self, frame: Vec<u8>) {
+        if let Some(upstream) = &*self.upstream.lock().unwrap() {
+            // Nobody listening is fine
+            let _ = upstream.send(frame);
+        }
+    }
+
+    pub(crate) fn close(&self) {
+        self.connected.store(false, Ordering::SeqCst);
+        self.upstream.lock().unwrap().take();
+    }
+
+    pub(crate) fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn notifications(&self) -> Result<NotificationStream<Vec<u8>>> {
+        let receiver = self
+            .upstream
+            .lock()
+            .unwrap()
+            .as_ref()
+            .ok_or_else(|| Error::HubError("Hub is disconnected".to_string()))?
+            .subscribe();
+        Ok(Box::pin(futures::stream::unfold(
+            receiver,
+            |mut receiver| async move {
+                loop {
+                    match receiver.recv().await {
+                        Ok(frame) => return Some((frame, receiver)),
+                        Err(RecvError::Lagged(n)) => {
+                            warn!("Notification stream missed {} frames", n)
+                        }
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            },
+        )))
+    }
+}
@@ -2,18 +2,75 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+//! Derives for the parse and serialise halves of the message formats.
+//!
+//! On a fieldless `#[repr(u8)]` enum the derives read and write a single
+//! byte. On a struct with named fields each field is read and written in
+//! declaration order, encoded according to its type:
+//!
+//! * `u8`, `i8`, `u16`, `i16`, `u32`, `i32`, `f32` - little-endian
+//! * `bool` - one byte, 0 or 1
+//! * `[u8; N]` - N bytes
+//! * `Vec<u8>` - the rest of the message, so only as the last field
+//! * anything else - a nested type with its own `parse` and `serialise`
+//!
+//! Fields can change their layout with `#[lpu(...)]`:
+//!
+//! * `big_endian` - multi-byte numbers are big-endian
+//! * `width = N` - an integer takes N bytes rather than its type's size;
+//!   signed values are sign-extended when read, and values are truncated to
+//!   N bytes when written
+//! * `repr = "u16"` - a fieldless `Copy` enum field is read and written as a
+//!   `u8`, `u16` or `u32`, for enums wider than the one byte the enum
+//!   derives use
+//! * `length_prefixed` - a `Vec<u8>` follows a one byte length rather than
+//!   taking the rest of the message, so it can go anywhere in the struct;
+//!   serialising one longer than 255 bytes panics
+//!
+//! A struct that is a whole message takes the message type as
+//! `#[lpu(message_type = "HubAttachedIo")]`, which puts the frame header in
+//! front of the serialised fields. Parsing starts after the header either
+//! way, as `NotificationMessage::parse` has consumed it.
+//!
+//! The generated code expects the `next!` family of macros, `ok!`,
+//! `FromPrimitive`, `Result`, `crate::error::Error` and, for message types,
+//! `MessageType` to be in scope where the derive is used.
+
 extern crate proc_macro;
 
 use proc_macro::TokenStream;
-use quote::quote;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{Data, DeriveInput, Error, Fields, Lit, Meta, NestedMeta, Type};
 
-#[proc_macro_derive(Parse)]
+#[proc_macro_derive(Parse, attributes(lpu))]
 pub fn parse_derive(input: TokenStream) -> TokenStream {
-    let ast: syn::DeriveInput = syn::parse(input).unwrap();
+    let ast: DeriveInput = syn::parse(input).unwrap();
+
+    match &ast.data {
+        Data::Enum(_) => parse_enum(&ast),
+        Data::Struct(_) => parse_struct(&ast).unwrap_or_else(|e| e.into()),
+        Data::Union(_) => unsupported(&ast),
+    }
+    .into()
+}
+
+#[proc_macro_derive(Serialise, attributes(lpu))]
+pub fn serialise_derive(input: TokenStream) -> TokenStream {
+    let ast: DeriveInput = syn::parse(input).unwrap();
+
+    match &ast.data {
+        Data::Enum(_) => serialise_enum(&ast),
+        Data::Struct(_) => serialise_struct(&ast).unwrap_or_else(|e| e.into()),
+        Data::Union(_) => unsupported(&ast),
+    }
+    .into()
+}
 
+fn parse_enum(ast: &DeriveInput) -> TokenStream2 {
     let name = &ast.ident;
     let trace_msg = format!("PARSE {}: {{}}", name);
-    let gen = quote! {
+    quote! {
         impl #name {
             pub fn parse<'a>(mut msg: impl Iterator<Item = &'a u8>) ->
                 Result<Self> {
@@ -22,7 +79,510 @@ pub fn parse_derive(input: TokenStream) -> TokenStream {
                 Ok(ok!(Self::from_u8(val)))
             }
         }
+    }
+}
+
+fn serialise_enum(ast: &DeriveInput) -> TokenStream2 {
+    let name = &ast.ident;
+    quote! {
+        impl #name {
+            pub fn serialise(&self) -> Vec<u8> {
+                vec![*self as u8]
+            }
+        }
+    }
+}
+
+fn parse_struct(ast: &DeriveInput) -> Result<TokenStream2, CompileError> {
+    let name = &ast.ident;
+    let fields = struct_fields(ast)?;
+    let trace_msg = format!("PARSE {}", name);
+
+    let mut reads = Vec::new();
+    let mut idents = Vec::new();
+    for field in &fields {
+        let ident = &field.ident;
+        let field_name = ident.to_string();
+        let read = match &field.encoding {
+            _ if field.is_laid_out() => read_number(field),
+            Encoding::U8 => quote! { next!(msg) },
+            Encoding::I8 => quote! { next_i8!(msg) },
+            Encoding::U16 => quote! { next_u16!(msg) },
+            Encoding::I16 => quote! { next_i16!(msg) },
+            Encoding::U32 => quote! { next_u32!(msg) },
+            Encoding::I32 => quote! { next_i32!(msg) },
+            Encoding::F32 => quote! { next_f32!(msg) },
+            Encoding::Bool => quote! {
+                match next!(msg) {
+                    0 => false,
+                    1 => true,
+                    v => {
+                        return Err(crate::error::Error::ParseError(format!(
+                            "Invalid value for {}: {}",
+                            #field_name, v
+                        )))
+                    }
+                }
+            },
+            Encoding::Array(len) => quote! {{
+                let mut bytes = [0_u8; #len];
+                for ele in bytes.iter_mut() {
+                    *ele = next!(msg);
+                }
+                bytes
+            }},
+            Encoding::Rest => quote! { msg.by_ref().copied().collect() },
+            Encoding::Prefixed => quote! {{
+                let len = next!(msg) as usize;
+                let mut bytes = Vec::with_capacity(len);
+                for _ in 0..len {
+                    bytes.push(next!(msg));
+                }
+                bytes
+            }},
+            Encoding::Nested(ty) => quote! { <#ty>::parse(&mut msg)? },
+            Encoding::Enum(..) => unreachable!(),
+        };
+        reads.push(quote! { let #ident = #read; });
+        idents.push(ident);
+    }
+
+    Ok(quote! {
+        impl #name {
+            pub fn parse<'a>(mut msg: impl Iterator<Item = &'a u8>) ->
+                Result<Self> {
+                log::trace!(#trace_msg);
+                #(#reads)*
+                Ok(Self { #(#idents),* })
+            }
+        }
+    })
+}
+
+fn serialise_struct(ast: &DeriveInput) -> Result<TokenStream2, CompileError> {
+    let name = &ast.ident;
+    let fields = struct_fields(ast)?;
+
+    let header = match message_type(ast)? {
+        Some(message_type) => {
+            let message_type = format_ident!("{}", message_type);
+            quote! { vec![0, 0, MessageType::#message_type as u8] }
+        }
+        None => quote! { Vec::new() },
     };
 
-    gen.into()
+    let writes = fields.iter().map(|field| {
+        let ident = &field.ident;
+        match &field.encoding {
+            _ if field.is_laid_out() => write_number(field),
+            Encoding::U8 | Encoding::Bool => quote! {
+                msg.push(self.#ident as u8);
+            },
+            Encoding::I8
+            | Encoding::U16
+            | Encoding::I16
+            | Encoding::U32
+            | Encoding::I32
+            | Encoding::F32 => quote! {
+                msg.extend_from_slice(&self.#ident.to_le_bytes());
+            },
+            Encoding::Array(_) | Encoding::Rest => quote! {
+                msg.extend_from_slice(&self.#ident);
+            },
+            Encoding::Prefixed => {
+                let too_long = format!(
+                    "{} is longer than its one byte length prefix allows",
+                    ident
+                );
+                quote! {
+                    msg.push(
+                        u8::try_from(self.#ident.len()).expect(#too_long),
+                    );
+                    msg.extend_from_slice(&self.#ident);
+                }
+            }
+            Encoding::Nested(_) => quote! {
+                msg.extend_from_slice(&self.#ident.serialise());
+            },
+            Encoding::Enum(..) => unreachable!(),
+        }
+    });
+
+    Ok(quote! {
+        impl #name {
+            pub fn serialise(&self) -> Vec<u8> {
+                let mut msg: Vec<u8> = #header;
+                #(#writes)*
+                msg
+            }
+        }
+    })
+}
+
+/// How a struct field is laid out in the message
+enum Encoding {
+    U8,
+    I8,
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+    Bool,
+    Array(syn::Expr),
+    Rest,
+    /// `Vec<u8>` after a one byte length
+    Prefixed,
+    Nested(Type),
+    /// Fieldless enum read and written as the integer type
+    Enum(Type, syn::Ident),
+}
+
+/// A struct field and how it is laid out in the message
+struct Field {
+    ident: syn::Ident,
+    encoding: Encoding,
+    big_endian: bool,
+    /// Bytes an integer takes, if not its type's size
+    width: Option<usize>,
+}
+
+impl Field {
+    /// Whether the field is a number laid out other than in the default
+    /// little-endian, full width way of the `next!` macros
+    fn is_laid_out(&self) -> bool {
+        matches!(self.encoding, Encoding::Enum(..))
+            || (self.encoding.number().is_some()
+                && (self.big_endian || self.width.is_some()))
+    }
+}
+
+impl Encoding {
+    fn of(ty: &Type) -> Self {
+        match ty {
+            Type::Array(array) if is_u8(&array.elem) => {
+                Encoding::Array(array.len.clone())
+            }
+            Type::Path(path) => {
+                let Some(last) = path.path.segments.last() else {
+                    return Encoding::Nested(ty.clone());
+                };
+                match last.ident.to_string().as_str() {
+                    "u8" => Encoding::U8,
+                    "i8" => Encoding::I8,
+                    "u16" => Encoding::U16,
+                    "i16" => Encoding::I16,
+                    "u32" => Encoding::U32,
+                    "i32" => Encoding::I32,
+                    "f32" => Encoding::F32,
+                    "bool" => Encoding::Bool,
+                    "Vec" if is_vec_of_u8(last) => Encoding::Rest,
+                    _ => Encoding::Nested(ty.clone()),
+                }
+            }
+            _ => Encoding::Nested(ty.clone()),
+        }
+    }
+
+    /// Type, size in bytes and signedness of a number
+    fn number(&self) -> Option<(TokenStream2, usize, bool)> {
+        Some(match self {
+            Encoding::U8 => (quote!(u8), 1, false),
+            Encoding::I8 => (quote!(i8), 1, true),
+            Encoding::U16 => (quote!(u16), 2, false),
+            Encoding::I16 => (quote!(i16), 2, true),
+            Encoding::U32 => (quote!(u32), 4, false),
+            Encoding::I32 => (quote!(i32), 4, true),
+            Encoding::F32 => (quote!(f32), 4, false),
+            Encoding::Enum(_, repr) => {
+                let size = match repr.to_string().as_str() {
+                    "u8" => 1,
+                    "u16" => 2,
+                    _ => 4,
+                };
+                (quote!(#repr), size, false)
+            }
+            _ => return None,
+        })
+    }
+}
+
+/// Read a number of `field.width` bytes into its type, sign-extending
+/// signed integers, and convert an enum from its integer
+fn read_number(field: &Field) -> TokenStream2 {
+    let (ty, size, signed) = field.encoding.number().unwrap();
+    let width = field.width.unwrap_or(size);
+    // Where the bytes read go, and where the sign bit ends up
+    let (first, sign) = if field.big_endian {
+        (size - width, size - width)
+    } else {
+        (0, width - 1)
+    };
+    let extend = if signed && width < size {
+        let fill = if field.big_endian {
+            quote! { ..#first }
+        } else {
+            quote! { #width.. }
+        };
+        quote! {
+            if bytes[#sign] & 0x80 != 0 {
+                bytes[#fill].fill(0xff);
+            }
+        }
+    } else {
+        quote! {}
+    };
+    let from_bytes = if field.big_endian {
+        quote! { from_be_bytes }
+    } else {
+        quote! { from_le_bytes }
+    };
+    let value = quote! {{
+        let mut bytes = [0_u8; #size];
+        for byte in bytes[#first..#first + #width].iter_mut() {
+            *byte = next!(msg);
+        }
+        #extend
+        <#ty>::#from_bytes(bytes)
+    }};
+    match &field.encoding {
+        Encoding::Enum(enum_ty, repr) => {
+            let from = format_ident!("from_{}", repr);
+            quote! { ok!(<#enum_ty>::#from(#value)) }
+        }
+        _ => value,
+    }
+}
+
+/// Write the bytes of a number that `read_number` reads back
+fn write_number(field: &Field) -> TokenStream2 {
+    let ident = &field.ident;
+    let (ty, size, _) = field.encoding.number().unwrap();
+    let width = field.width.unwrap_or(size);
+    let value = match &field.encoding {
+        Encoding::Enum(..) => quote! { (self.#ident as #ty) },
+        _ => quote! { self.#ident },
+    };
+    if field.big_endian {
+        let first = size - width;
+        quote! {
+            msg.extend_from_slice(&#value.to_be_bytes()[#first..]);
+        }
+    } else {
+        quote! {
+            msg.extend_from_slice(&#value.to_le_bytes()[..#width]);
+        }
+    }
+}
+
+fn is_u8(ty: &Type) -> bool {
+    matches!(ty, Type::Path(path) if path.path.is_ident("u8"))
+}
+
+fn is_vec_of_u8(segment: &syn::PathSegment) -> bool {
+    match &segment.arguments {
+        syn::PathArguments::AngleBracketed(args) => {
+            matches!(
+                args.args.first(),
+                Some(syn::GenericArgument::Type(ty)) if is_u8(ty)
+            ) && args.args.len() == 1
+        }
+        _ => false,
+    }
+}
+
+struct CompileError(Error);
+
+impl From<Error> for CompileError {
+    fn from(e: Error) -> Self {
+        Self(e)
+    }
+}
+
+impl From<CompileError> for TokenStream2 {
+    fn from(e: CompileError) -> Self {
+        e.0.to_compile_error()
+    }
+}
+
+fn unsupported(ast: &DeriveInput) -> TokenStream2 {
+    Error::new_spanned(&ast.ident, "unions are not supported")
+        .to_compile_error()
+}
+
+/// The named fields of a struct with their encodings. A trailing `Vec<u8>`
+/// takes the rest of the message, so it can only come last.
+fn struct_fields(ast: &DeriveInput) -> Result<Vec<Field>, CompileError> {
+    let Data::Struct(data) = &ast.data else {
+        unreachable!()
+    };
+    let Fields::Named(named) = &data.fields else {
+        return Err(Error::new_spanned(
+            &ast.ident,
+            "only structs with named fields are supported",
+        )
+        .into());
+    };
+
+    let count = named.named.len();
+    let mut fields = Vec::with_capacity(count);
+    for (i, field) in named.named.iter().enumerate() {
+        let field = field_layout(field)?;
+        if matches!(field.encoding, Encoding::Rest) && i + 1 != count {
+            return Err(Error::new_spanned(
+                &field.ident,
+                "a Vec<u8> field takes the rest of the message, so it must \
+                 be the last field or length_prefixed",
+            )
+            .into());
+        }
+        fields.push(field);
+    }
+    Ok(fields)
+}
+
+/// A field's encoding, adjusted by its `#[lpu(...)]` attributes
+fn field_layout(field: &syn::Field) -> Result<Field, CompileError> {
+    let mut encoding = Encoding::of(&field.ty);
+    let mut big_endian = false;
+    let mut width = None;
+    for attr in field.attrs.iter().filter(|a| a.path.is_ident("lpu")) {
+        let Meta::List(list) = attr.parse_meta()? else {
+            return Err(Error::new_spanned(attr, "expected #[lpu(...)]").into());
+        };
+        for nested in list.nested {
+            let NestedMeta::Meta(meta) = nested else {
+                return Err(Error::new_spanned(
+                    nested,
+                    "unknown lpu field attribute",
+                )
+                .into());
+            };
+            match &meta {
+                Meta::Path(path) if path.is_ident("big_endian") => {
+                    big_endian = true;
+                }
+                Meta::Path(path) if path.is_ident("length_prefixed") => {
+                    if !matches!(encoding, Encoding::Rest) {
+                        return Err(Error::new_spanned(
+                            meta,
+                            "length_prefixed only applies to Vec<u8> fields",
+                        )
+                        .into());
+                    }
+                    encoding = Encoding::Prefixed;
+                }
+                Meta::NameValue(nv) if nv.path.is_ident("width") => {
+                    let Lit::Int(lit) = &nv.lit else {
+                        return Err(Error::new_spanned(
+                            &nv.lit,
+                            "width must be a number of bytes",
+                        )
+                        .into());
+                    };
+                    width = Some((lit.base10_parse::<usize>()?, lit.clone()));
+                }
+                Meta::NameValue(nv) if nv.path.is_ident("repr") => {
+                    let repr = match &nv.lit {
+                        Lit::Str(s)
+                            if ["u8", "u16", "u32"]
+                                .contains(&s.value().as_str()) =>
+                        {
+                            format_ident!("{}", s.value())
+                        }
+                        lit => {
+                            return Err(Error::new_spanned(
+                                lit,
+                                "repr must be \"u8\", \"u16\" or \"u32\"",
+                            )
+                            .into())
+                        }
+                    };
+                    let Encoding::Nested(ty) = encoding else {
+                        return Err(Error::new_spanned(
+                            meta,
+                            "repr only applies to enum fields",
+                        )
+                        .into());
+                    };
+                    encoding = Encoding::Enum(ty, repr);
+                }
+                _ => {
+                    return Err(Error::new_spanned(
+                        meta,
+                        "unknown lpu field attribute",
+                    )
+                    .into())
+                }
+            }
+        }
+    }
+
+    let number = encoding.number();
+    if big_endian && number.is_none() {
+        return Err(Error::new_spanned(
+            &field.ty,
+            "big_endian only applies to numbers and repr enums",
+        )
+        .into());
+    }
+    let width = match width {
+        None => None,
+        Some((width, lit)) => match number {
+            Some((_, size, _))
+                if !matches!(encoding, Encoding::F32 | Encoding::Enum(..))
+                    && (1..=size).contains(&width) =>
+            {
+                Some(width)
+            }
+            _ => {
+                return Err(Error::new_spanned(
+                    lit,
+                    "width must be from 1 up to the size of an integer field",
+                )
+                .into())
+            }
+        },
+    };
+
+    Ok(Field {
+        ident: field.ident.clone().unwrap(),
+        encoding,
+        big_endian,
+        width,
+    })
+}
+
+/// The `message_type` from a struct's `#[lpu(...)]` attribute, if any
+fn message_type(ast: &DeriveInput) -> Result<Option<String>, CompileError> {
+    let mut message_type = None;
+    for attr in ast.attrs.iter().filter(|a| a.path.is_ident("lpu")) {
+        let Meta::List(list) = attr.parse_meta()? else {
+            return Err(Error::new_spanned(attr, "expected #[lpu(...)]").into());
+        };
+        for nested in list.nested {
+            match nested {
+                NestedMeta::Meta(Meta::NameValue(nv))
+                    if nv.path.is_ident("message_type") =>
+                {
+                    let Lit::Str(s) = nv.lit else {
+                        return Err(Error::new_spanned(
+                            nv.lit,
+                            "message_type must be a string",
+                        )
+                        .into());
+                    };
+                    message_type = Some(s.value());
+                }
+                other => {
+                    return Err(Error::new_spanned(
+                        other,
+                        "unknown lpu attribute",
+                    )
+                    .into())
+                }
+            }
+        }
+    }
+    Ok(message_type)
 }
@@ -0,0 +1,239 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The derives with the helpers that lego-powered-up provides stood in for
+//! here
+
+use lpu_macros::{Parse, Serialise};
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+
+mod error {
+    #[derive(Debug, PartialEq, Eq)]
+    pub enum Error {
+        ParseError(String),
+        NoneError(String),
+    }
+}
+
+use error::Error;
+
+type Result<T> = std::result::Result<T, Error>;
+
+trait OptionContext<T> {
+    fn context(self, ctx: &str) -> Result<T>;
+}
+
+impl<T> OptionContext<T> for Option<T> {
+    fn context(self, ctx: &str) -> Result<T> {
+        self.ok_or_else(|| Error::NoneError(ctx.to_string()))
+    }
+}
+
+macro_rules! ok {
+    ($thing:expr) => {
+        $thing.context("Cannot convert 'None'")?
+    };
+}
+
+macro_rules! next {
+    ($iter:ident) => {
+        *$iter.next().context("Insufficient length")?
+    };
+}
+
+macro_rules! next_u16 {
+    ($iter:ident) => {
+        u16::from_le_bytes([next!($iter), next!($iter)])
+    };
+}
+
+macro_rules! next_i32 {
+    ($iter:ident) => {
+        i32::from_le_bytes([
+            next!($iter),
+            next!($iter),
+            next!($iter),
+            next!($iter),
+        ])
+    };
+}
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum MessageType {
+    HubAttachedIo = 0x04,
+}
+
+#[repr(u8)]
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, FromPrimitive, Parse, Serialise,
+)]
+enum Colour {
+    Red = 0x01,
+    Blue = 0x02,
+}
+
+/// Wider than a byte, like `IoTypeId`
+#[repr(u16)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, FromPrimitive)]
+enum Device {
+    Motor = 0x0001,
+    Sensor = 0x0102,
+}
+
+#[derive(Debug, PartialEq, Parse, Serialise)]
+struct Defaults {
+    id: u8,
+    count: u16,
+    position: i32,
+    on: bool,
+    colour: Colour,
+    rest: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Parse, Serialise)]
+#[lpu(message_type = "HubAttachedIo")]
+struct Attached {
+    port_id: u8,
+    #[lpu(repr = "u16")]
+    device: Device,
+    #[lpu(repr = "u16", big_endian)]
+    device_be: Device,
+    #[lpu(big_endian)]
+    count: u32,
+    #[lpu(width = 3)]
+    small: u32,
+    #[lpu(width = 3, big_endian)]
+    small_be: i32,
+    #[lpu(width = 1)]
+    signed: i16,
+    #[lpu(length_prefixed)]
+    name: Vec<u8>,
+    id: u8,
+}
+
+#[test]
+fn defaults() {
+    let bytes = [
+        0x07, 0x34, 0x12, 0xfe, 0xff, 0xff, 0xff, 0x01, 0x02, 0xaa, 0xbb,
+    ];
+    let value = Defaults::parse(bytes.iter()).unwrap();
+    assert_eq!(
+        value,
+        Defaults {
+            id: 7,
+            count: 0x1234,
+            position: -2,
+            on: true,
+            colour: Colour::Blue,
+            rest: vec![0xaa, 0xbb],
+        }
+    );
+    assert_eq!(value.serialise(), bytes);
+
+    // Bools and enums reject values they don't have
+    let mut bad = bytes;
+    bad[7] = 2;
+    assert!(matches!(
+        Defaults::parse(bad.iter()),
+        Err(Error::ParseError(_))
+    ));
+    let mut bad = bytes;
+    bad[8] = 3;
+    assert!(matches!(
+        Defaults::parse(bad.iter()),
+        Err(Error::NoneError(_))
+    ));
+}
+
+#[test]
+fn field_attributes() {
+    let body = [
+        0x05, // port_id
+        0x02, 0x01, // device, little-endian
+        0x01, 0x02, // device_be
+        0x12, 0x34, 0x56, 0x78, // count
+        0x01, 0x02, 0x03, // small
+        0xff, 0xff, 0xfe, // small_be
+        0x80, // signed
+        0x02, 0x61, 0x62, // name
+        0x09, // id
+    ];
+    let value = Attached::parse(body.iter()).unwrap();
+    assert_eq!(
+        value,
+        Attached {
+            port_id: 5,
+            device: Device::Sensor,
+            device_be: Device::Sensor,
+            count: 0x1234_5678,
+            small: 0x0003_0201,
+            small_be: -2,
+            signed: -128,
+            name: b"ab".to_vec(),
+            id: 9,
+        }
+    );
+
+    let mut message = vec![0, 0, MessageType::HubAttachedIo as u8];
+    message.extend_from_slice(&body);
+    assert_eq!(value.serialise(), message);
+}
+
+#[test]
+fn field_attribute_errors() {
+    // A u16 that no enum variant has
+    let mut body = [
+        0x05, 0x03, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    assert!(matches!(
+        Attached::parse(body.iter()),
+        Err(Error::NoneError(_))
+    ));
+
+    // A length prefix longer than the bytes that follow
+    body[1] = 0x01;
+    body[16] = 0x05;
+    assert!(matches!(
+        Attached::parse(body.iter()),
+        Err(Error::NoneError(_))
+    ));
+}
+
+fn narrow(name: Vec<u8>) -> Attached {
+    Attached {
+        port_id: 0,
+        device: Device::Motor,
+        device_be: Device::Motor,
+        count: 0,
+        small: 0xaabb_ccdd,
+        small_be: 0x0102_0304,
+        signed: 0x1234,
+        name,
+        id: 0,
+    }
+}
+
+#[test]
+fn narrow_values_truncated() {
+    let bytes = narrow(Vec::new()).serialise();
+    assert_eq!(bytes[4..8], [0x01, 0x00, 0x00, 0x01]);
+    assert_eq!(bytes[12..19], [0xdd, 0xcc, 0xbb, 0x02, 0x03, 0x04, 0x34]);
+    assert_eq!(bytes[19..], [0, 0]);
+
+    // The longest vector the prefix can count
+    let value = narrow(vec![0x55; 255]);
+    let bytes = value.serialise();
+    assert_eq!(bytes[19], 0xff);
+    assert_eq!(bytes.len(), 19 + 1 + 255 + 1);
+    let parsed = Attached::parse(bytes[3..].iter()).unwrap();
+    assert_eq!(parsed.name, value.name);
+}
+
+#[test]
+#[should_panic(expected = "name is longer than its one byte length prefix")]
+fn length_prefix_too_long() {
+    narrow(vec![0x55; 256]).serialise();
+}
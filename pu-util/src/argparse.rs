@@ -11,9 +11,21 @@ pub struct Args {
 }
 
 pub enum Command {
+    Decode(DecodeArgs),
+    DecodeBtsnoop(DecodeBtsnoopArgs),
     Devices(DevicesArgs),
     Hubs(HubArgs),
     MotorTest(MotorTestArgs),
+    Rename(RenameArgs),
+}
+
+pub struct DecodeArgs {
+    pub frames: Vec<String>,
+}
+
+pub struct DecodeBtsnoopArgs {
+    pub file: String,
+    pub handle: Option<u16>,
 }
 
 pub struct DevicesArgs {
@@ -32,6 +44,13 @@ pub struct MotorTestArgs {
     pub address: Option<String>,
 }
 
+pub struct RenameArgs {
+    pub device_index: Option<usize>,
+    pub address: Option<String>,
+    pub name: Option<String>,
+    pub new_name: String,
+}
+
 pub fn parse_args() -> Args {
     let matches = App::new("PoweredUp Util")
         .version(crate_version!())
@@ -44,6 +63,39 @@ pub fn parse_args() -> Args {
                 .action(ArgAction::Count)
                 .help("Increase verbosity"),
         )
+        .subcommand(
+            App::new("decode")
+                .about("Decode LWP3 frames given in hex")
+                .after_help(
+                    "LWP3 frames carry no checksum; the Bluetooth link \
+                    checks them, so none is shown.",
+                )
+                .arg(
+                    Arg::new("frames")
+                        .help(
+                            "Frame bytes in hex, read from stdin if not given",
+                        )
+                        .multiple_values(true),
+                ),
+        )
+        .subcommand(
+            App::new("decode-btsnoop")
+                .about("Decode the LWP3 traffic in a btsnoop HCI log")
+                .arg(
+                    Arg::new("handle")
+                        .long("handle")
+                        .help(
+                            "Attribute handle of the LPF2_ALL characteristic, \
+                            if the log doesn't include service discovery",
+                        )
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("file")
+                        .help("Log file, such as Android's btsnoop_hci.log")
+                        .required(true),
+                ),
+        )
         .subcommand(
             App::new("devices")
                 .about("Information about connected Bluetooth devices")
@@ -90,6 +142,33 @@ pub fn parse_args() -> Args {
                         .takes_value(true),
                 ),
         )
+        .subcommand(
+            App::new("rename")
+                .about("Set the advertising name of a hub")
+                .arg(
+                    Arg::new("device")
+                        .long("device")
+                        .help("Device index (from `devices`)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("name")
+                        .long("name")
+                        .help("Current name of the hub")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("address")
+                        .long("address")
+                        .help("Address of hub")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("new_name")
+                        .help("New name, up to 14 ASCII characters")
+                        .required(true),
+                ),
+        )
         .get_matches();
 
     let verbosity = min(matches.occurrences_of("verbose"), 2);
@@ -118,6 +197,34 @@ pub fn parse_args() -> Args {
             }),
             address: matches.value_of("address").map(String::from),
         })
+    } else if let Some(matches) = matches.subcommand_matches("rename") {
+        Command::Rename(RenameArgs {
+            device_index: matches.value_of("device").map(|v| {
+                v.parse()
+                    .expect("Device index must be a nonnegative integer")
+            }),
+            address: matches.value_of("address").map(String::from),
+            name: matches.value_of("name").map(String::from),
+            new_name: matches.value_of("new_name").unwrap().to_string(),
+        })
+    } else if let Some(matches) = matches.subcommand_matches("decode") {
+        Command::Decode(DecodeArgs {
+            frames: matches
+                .values_of("frames")
+                .map(|v| v.map(String::from).collect())
+                .unwrap_or_default(),
+        })
+    } else if let Some(matches) = matches.subcommand_matches("decode-btsnoop") {
+        Command::DecodeBtsnoop(DecodeBtsnoopArgs {
+            file: matches.value_of("file").unwrap().to_string(),
+            handle: matches.value_of("handle").map(|v| {
+                match v.strip_prefix("0x") {
+                    Some(hex) => u16::from_str_radix(hex, 16),
+                    None => v.parse(),
+                }
+                .expect("Handle must be a 16 bit number")
+            }),
+        })
     } else {
         unreachable!();
     };
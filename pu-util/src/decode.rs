@@ -0,0 +1,376 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::argparse::DecodeArgs;
+use anyhow::{bail, Result};
+use lego_powered_up::consts::{HubType, MessageType};
+use lego_powered_up::hubs::{Port, PortMap};
+use lego_powered_up::notifications::{
+    FrameDecoder, NotificationMessage, PortOutputSubcommand,
+};
+use num_traits::FromPrimitive;
+use std::fmt::Write;
+use std::io::Read;
+
+pub fn run(args: &DecodeArgs) -> Result<()> {
+    let input = if args.frames.is_empty() {
+        let mut input = String::new();
+        std::io::stdin().read_to_string(&mut input)?;
+        input
+    } else {
+        args.frames.join(" ")
+    };
+
+    let bytes = parse_hex(&input)?;
+    for chunk in split(&bytes) {
+        match chunk {
+            Chunk::Frame(frame) => println!("{}", describe(&frame)),
+            Chunk::Unknown {
+                offset,
+                bytes,
+                reason,
+            } => println!(
+                "unknown input bytes at {}: {}, {}\n",
+                offset,
+                hex(&bytes),
+                reason
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// Bytes in hex, separated by whitespace, commas or brackets or not at all,
+/// optionally with a `0x` prefix
+fn parse_hex(input: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for token in input
+        .split(|c: char| c.is_whitespace() || ",[]".contains(c))
+        .filter(|t| !t.is_empty())
+    {
+        let digits = token
+            .strip_prefix("0x")
+            .or_else(|| token.strip_prefix("0X"))
+            .unwrap_or(token);
+        if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("`{}` is not hex", token);
+        }
+        if digits.len() == 1 {
+            bytes.push(u8::from_str_radix(digits, 16)?);
+            continue;
+        }
+        if digits.len() % 2 != 0 {
+            bail!("`{}` is not a whole number of hex bytes", token);
+        }
+        for i in (0..digits.len()).step_by(2) {
+            bytes.push(u8::from_str_radix(&digits[i..i + 2], 16)?);
+        }
+    }
+    Ok(bytes)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// A piece of the input, in input order
+#[derive(Debug, PartialEq, Eq)]
+enum Chunk {
+    Frame(Vec<u8>),
+    Unknown {
+        offset: usize,
+        bytes: Vec<u8>,
+        reason: &'static str,
+    },
+}
+
+/// Split the input into frames and the bytes between them that aren't
+/// frames
+fn split(bytes: &[u8]) -> Vec<Chunk> {
+    let mut chunks = Vec::new();
+    let mut decoder = FrameDecoder::new();
+    decoder.push(bytes);
+    let mut offset = 0;
+    loop {
+        let before = decoder.buffered();
+        let frame = decoder.next_frame();
+        let consumed = before - decoder.buffered();
+        let skipped = consumed - frame.as_ref().map_or(0, Vec::len);
+        if skipped > 0 {
+            chunks.push(Chunk::Unknown {
+                offset,
+                bytes: bytes[offset..offset + skipped].to_vec(),
+                reason: "not a valid length header",
+            });
+        }
+        offset += consumed;
+        let Some(frame) = frame else {
+            break;
+        };
+        chunks.push(Chunk::Frame(frame));
+    }
+    if offset < bytes.len() {
+        chunks.push(Chunk::Unknown {
+            offset,
+            bytes: bytes[offset..].to_vec(),
+            reason: "too few for the frame they start",
+        });
+    }
+    chunks
+}
+
+/// Hub types whose ports have names
+const HUB_TYPES: [HubType; 5] = [
+    HubType::TechnicMediumHub,
+    HubType::MoveHub,
+    HubType::Hub,
+    HubType::RemoteControl,
+    HubType::DuploTrainBase,
+];
+
+/// The name of a port on each hub type that names it. A frame doesn't say
+/// which hub it is from, and the internal ports sit at different ids on
+/// different hubs.
+fn port_names(port_id: u8) -> String {
+    let mut names: Vec<(Port, Vec<String>)> = Vec::new();
+    for hub_type in HUB_TYPES {
+        let port = PortMap::for_hub_type(hub_type).port(port_id);
+        if matches!(port, Port::Other(_)) {
+            continue;
+        }
+        match names.iter_mut().find(|(p, _)| *p == port) {
+            Some((_, hubs)) => hubs.push(hub_type.to_string()),
+            None => names.push((port, vec![hub_type.to_string()])),
+        }
+    }
+    if names.is_empty() {
+        return "unnamed".to_string();
+    }
+    names
+        .iter()
+        .map(|(port, hubs)| format!("{:?} on {}", port, hubs.join(", ")))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Name of an enum variant from its debug output
+fn variant_name(debug: &str) -> &str {
+    debug
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .next()
+        .unwrap_or(debug)
+}
+
+/// Print a multi-line debug dump as a labelled field
+fn dump(out: &mut String, label: &str, debug: &str) {
+    let mut lines = debug.lines();
+    let _ =
+        writeln!(out, "  {:13} {}", label, lines.next().unwrap_or_default());
+    for line in lines {
+        let _ = writeln!(out, "                {}", line);
+    }
+}
+
+/// The subcommand of an output command, named and numbered as on the wire,
+/// with the mode that direct mode data is written in
+fn describe_subcommand(out: &mut String, subcommand: &PortOutputSubcommand) {
+    let wire = subcommand.serialise();
+    let name = format!("{:?}", subcommand);
+    let _ = writeln!(
+        out,
+        "  subcommand    0x{:02x} {}",
+        wire[0],
+        variant_name(&name)
+    );
+    match subcommand {
+        PortOutputSubcommand::WriteDirectModeData(payload) => {
+            let _ = writeln!(out, "  mode          {}", wire[1]);
+            dump(out, "data", &format!("{:#?}", payload));
+        }
+        _ => dump(out, "parameters", &format!("{:#?}", subcommand)),
+    }
+}
+
+fn describe(frame: &[u8]) -> String {
+    let mut out = String::new();
+    // The decoder only hands out frames with a valid length header and at
+    // least a hub id and message type after it
+    let header_len = if frame[0] & 0x80 == 0 { 1 } else { 2 };
+    let _ = writeln!(out, "{}", hex(frame));
+    let _ = writeln!(out, "  length        {}", frame.len());
+    let _ = writeln!(out, "  hub id        {}", frame[header_len]);
+    let type_byte = frame[header_len + 1];
+    let _ = match MessageType::from_u8(type_byte) {
+        Some(message_type) => writeln!(
+            out,
+            "  message type  0x{:02x} {:?}",
+            type_byte, message_type
+        ),
+        None => writeln!(out, "  message type  0x{:02x} unknown", type_byte),
+    };
+    let body = header_len + 2;
+
+    let message = match NotificationMessage::parse(frame) {
+        Ok(message) => message,
+        Err(e) => {
+            let _ = writeln!(out, "  error         {}", e);
+            if body < frame.len() {
+                let _ = writeln!(
+                    out,
+                    "  unknown bytes {}: {}",
+                    body,
+                    hex(&frame[body..])
+                );
+            }
+            return out;
+        }
+    };
+    if let Some(port_id) = message.port_id() {
+        let _ = writeln!(
+            out,
+            "  port          0x{:02x} {}",
+            port_id,
+            port_names(port_id)
+        );
+    }
+    match &message {
+        NotificationMessage::PortOutputCommand(cmd) => {
+            let _ = writeln!(out, "  startup       {:?}", cmd.startup_info);
+            let _ = writeln!(out, "  completion    {:?}", cmd.completion_info);
+            describe_subcommand(&mut out, &cmd.subcommand);
+        }
+        NotificationMessage::PortValueSingle(_) => {
+            // Only the first port id is known to be one, the rest of the
+            // payload is split by the ports' value formats
+            let _ = match frame.get(body + 1..).unwrap_or_default() {
+                [] => writeln!(out, "  value bytes   none"),
+                values => writeln!(
+                    out,
+                    "  value bytes   {}, lengths set by the value formats the \
+                    hub reports separately",
+                    hex(values)
+                ),
+            };
+        }
+        _ => dump(&mut out, "message", &format!("{:#?}", message)),
+    }
+
+    // Whatever the parser didn't consume is missing when the message is
+    // serialised again
+    let reencoded = message.serialise();
+    let reencoded = &reencoded[if reencoded[0] & 0x80 == 0 { 1 } else { 2 }..];
+    let original = &frame[header_len..];
+    if let Some(unknown) = original.strip_prefix(reencoded) {
+        if !unknown.is_empty() {
+            let _ = writeln!(
+                out,
+                "  unknown bytes {}: {}",
+                frame.len() - unknown.len(),
+                hex(unknown)
+            );
+        }
+    } else {
+        let offset = original
+            .iter()
+            .zip(reencoded)
+            .position(|(a, b)| a != b)
+            .unwrap_or(reencoded.len().min(original.len()));
+        let _ = writeln!(
+            out,
+            "  note          the decoded message serialises differently \
+            from byte {}: {}",
+            header_len + offset,
+            hex(reencoded)
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hex_input() {
+        let bytes = [0x05, 0x00, 0x45, 0x00, 0xff];
+        for input in [
+            "05 00 45 00 ff",
+            "0500 4500ff",
+            "[0x05, 0x00, 0x45, 0x00, 0xFF]",
+            "5,0,45,0,ff",
+            "0x0500\n4500ff\n",
+        ] {
+            assert_eq!(parse_hex(input).unwrap(), bytes, "{}", input);
+        }
+        assert!(parse_hex("").unwrap().is_empty());
+        assert!(parse_hex("05 0g").is_err());
+        assert!(parse_hex("050").is_err());
+    }
+
+    #[test]
+    fn unknown_input_bytes() {
+        // A zero length can't start a frame, and the last frame is cut short
+        let chunks = split(&[0x00, 0x04, 0x00, 0x02, 0x30, 0x05, 0x00, 0x45]);
+        assert_eq!(
+            chunks,
+            [
+                Chunk::Unknown {
+                    offset: 0,
+                    bytes: vec![0x00],
+                    reason: "not a valid length header",
+                },
+                Chunk::Frame(vec![0x04, 0x00, 0x02, 0x30]),
+                Chunk::Unknown {
+                    offset: 5,
+                    bytes: vec![0x05, 0x00, 0x45],
+                    reason: "too few for the frame they start",
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_frame_bytes() {
+        // A hub action with a byte after it that nothing reads
+        let out = describe(&[0x05, 0x00, 0x02, 0x30, 0x99]);
+        assert!(out.contains("unknown bytes 4: 99"), "{}", out);
+
+        // A message type that doesn't exist
+        let out = describe(&[0x05, 0x00, 0x7f, 0x01, 0x02]);
+        assert!(out.contains("message type  0x7f unknown"), "{}", out);
+        assert!(out.contains("unknown bytes 3: 01 02"), "{}", out);
+
+        let out = describe(&[0x04, 0x00, 0x02, 0x30]);
+        assert!(!out.contains("unknown"), "{}", out);
+    }
+
+    #[test]
+    fn output_command() {
+        let out = describe(&[0x08, 0x00, 0x81, 0x32, 0x11, 0x51, 0x00, 0x05]);
+        assert!(
+            out.contains("subcommand    0x51 WriteDirectModeData"),
+            "{}",
+            out
+        );
+        assert!(out.contains("mode          0\n"), "{}", out);
+        assert!(out.contains("StartPower("), "{}", out);
+        assert!(out.contains("port          0x32 HubLed"), "{}", out);
+    }
+
+    #[test]
+    fn empty_port_value() {
+        for frame in [&[0x03, 0x00, 0x45][..], &[0x04, 0x00, 0x45, 0x00]] {
+            let out = describe(frame);
+            assert!(out.contains("value bytes   none\n"), "{}", out);
+        }
+
+        let out = describe(&[0x05, 0x00, 0x45, 0x00, 0x07]);
+        assert!(out.contains("value bytes   07, lengths"), "{}", out);
+    }
+}
@@ -0,0 +1,33 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::argparse::DecodeBtsnoopArgs;
+use anyhow::Result;
+use lego_powered_up::btsnoop::Btsnoop;
+use lego_powered_up::capture::Direction;
+use lego_powered_up::notifications::NotificationMessage;
+
+pub fn run(args: &DecodeBtsnoopArgs) -> Result<()> {
+    let capture = Btsnoop::load(&args.file)?.lwp3_frames(args.handle)?;
+    println!("{} LWP3 frames", capture.entries.len());
+
+    for entry in &capture.entries {
+        let direction = match entry.direction {
+            Direction::Sent => "app -> hub",
+            Direction::Received => "hub -> app",
+        };
+        println!(
+            "{:12.6} {} {:02x?}",
+            entry.time.as_secs_f64(),
+            direction,
+            entry.frame
+        );
+        match NotificationMessage::parse(&entry.frame) {
+            Ok(message) => println!("{:23} {:?}", "", message),
+            Err(e) => println!("{:23} Failed to parse: {}", "", e),
+        }
+    }
+
+    Ok(())
+}
@@ -7,9 +7,12 @@ use argparse::Command;
 use env_logger::Env;
 
 mod argparse;
+mod decode;
+mod decode_btsnoop;
 mod devices;
 mod hubs;
 mod motor_test;
+mod rename;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -25,9 +28,14 @@ async fn main() -> Result<()> {
     .init();
 
     match args.command {
+        Command::Decode(decode_args) => decode::run(&decode_args)?,
+        Command::DecodeBtsnoop(decode_args) => {
+            decode_btsnoop::run(&decode_args)?
+        }
         Command::Devices(dev_args) => devices::run(&dev_args).await?,
         Command::Hubs(hub_args) => hubs::run(&hub_args).await?,
         Command::MotorTest(mot_args) => motor_test::run(&mot_args).await?,
+        Command::Rename(rename_args) => rename::run(&rename_args).await?,
     }
 
     Ok(())
@@ -0,0 +1,43 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::argparse::RenameArgs;
+use anyhow::Result;
+use lego_powered_up::{HubFilter, PoweredUp};
+
+pub async fn run(args: &RenameArgs) -> Result<()> {
+    let mut pu = if let Some(dev) = args.device_index {
+        PoweredUp::with_device_index(dev).await?
+    } else {
+        PoweredUp::init().await?
+    };
+
+    println!("Listening for hub announcements...");
+
+    let hub = if let Some(addr) = &args.address {
+        pu.wait_for_hub_filter(HubFilter::Addr(addr.to_string()))
+            .await?
+    } else if let Some(name) = &args.name {
+        pu.wait_for_hub_filter(HubFilter::Name(name.to_string()))
+            .await?
+    } else {
+        pu.wait_for_hub().await?
+    };
+
+    println!(
+        "Connecting to `{}` `{}` with address `{}`",
+        hub.hub_type, hub.name, hub.addr
+    );
+    let hub = pu.create_hub(&hub).await?;
+
+    println!("Renaming to `{}`", args.new_name);
+    let result = hub.set_name(&args.new_name).await;
+
+    println!("Disconnecting...");
+    hub.disconnect().await?;
+    result?;
+    println!("Done");
+
+    Ok(())
+}